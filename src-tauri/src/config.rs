@@ -1,17 +1,239 @@
-use confy;
-use keyring::Entry;
+use std::time::{Duration, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+use crate::{
+    discord_api::{
+        api_client::{TokenData, DEFAULT_API_BASE_URL, DEFAULT_REDIRECT_URI},
+        retry::DEFAULT_RETRY_MAX_WAIT,
+    },
+    ipc::{
+        auth::{AuthError, AuthErrorType},
+        client::DEFAULT_REQUEST_TIMEOUT,
+        protocol::User,
+        scope,
+        voice_connection::DEFAULT_PING_WARNING,
+    },
+    secret::{self, SecretBackend, SecretError},
+};
+
+/// The profile of configs from before there were profiles, its refresh token
+/// stays in the keyring entry it always was in.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A voice channel to join with one click.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FavoriteChannel {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub guild_id: Option<String>,
+}
+
+/// A discord account the app can log in as.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// Who the profile last authenticated as, to tell the profiles apart without connecting.
+    #[serde(default)]
+    pub user: Option<User>,
+}
+
+impl Profile {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            user: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub refresh_token: String,
+    /// How long a command waits for discord to answer.
+    pub request_timeout_ms: u64,
+    /// A token broker holding the client secret, e.g. `http://127.0.0.1:8787/token`.
+    /// Without one the client secret is read from the environment.
+    pub token_broker_url: Option<String>,
+    /// Exchange the OAuth2 code with PKCE instead of the client secret.
+    /// On by default in builds with the `pkce` feature.
+    pub pkce: bool,
+    /// Discord's API, or a stand-in OAuth server in tests.
+    /// Overridden by `DISCORD_API_BASE_URL`.
+    pub api_base_url: String,
+    /// Must be one of the redirects registered for the app.
+    /// Overridden by `DISCORD_REDIRECT_URI`.
+    pub redirect_uri: String,
+    /// How long token requests may wait in total for rate limits and discord's
+    /// outages to pass before giving up.
+    pub token_retry_max_wait_ms: u64,
+    /// Where refresh tokens are kept. `Auto` uses the keyring, and an encrypted
+    /// file next to this config when there is no keyring to use.
+    pub secret_store: SecretBackend,
+    /// Asked for when authorizing, e.g. `rpc.activities.write` or `messages.read` on top of
+    /// the defaults. Changing them makes the next connection authorize again.
+    pub scopes: Vec<String>,
+    /// The profile `connect_ipc` authenticates with.
+    pub profile: String,
+    pub profiles: Vec<Profile>,
+    pub favorite_channels: Vec<FavoriteChannel>,
+    /// Warns about the voice connection once its average ping goes above this.
+    pub voice_ping_warning_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            refresh_token: String::new(),
+            request_timeout_ms: DEFAULT_REQUEST_TIMEOUT.as_millis() as u64,
+            token_broker_url: None,
+            pkce: cfg!(feature = "pkce"),
+            api_base_url: DEFAULT_API_BASE_URL.to_string(),
+            redirect_uri: DEFAULT_REDIRECT_URI.to_string(),
+            token_retry_max_wait_ms: DEFAULT_RETRY_MAX_WAIT.as_millis() as u64,
+            secret_store: SecretBackend::default(),
+            scopes: scope::default_scopes(),
+            profile: DEFAULT_PROFILE.to_string(),
+            profiles: vec![Profile::new(DEFAULT_PROFILE)],
+            favorite_channels: Vec::new(),
+            voice_ping_warning_ms: DEFAULT_PING_WARNING.as_millis() as u64,
+        }
+    }
+}
+
+impl Config {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
+    pub fn token_retry_max_wait(&self) -> Duration {
+        Duration::from_millis(self.token_retry_max_wait_ms)
+    }
+
+    pub fn voice_ping_warning(&self) -> Duration {
+        Duration::from_millis(self.voice_ping_warning_ms)
+    }
+
+    /// Applies the environment variables (or `.env`) that take precedence over the config file.
+    pub fn with_env_overrides(self) -> Self {
+        self.with_overrides(|name| dotenvy::var(name).ok())
+    }
+
+    fn with_overrides(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+        if let Some(url) = var("DISCORD_API_BASE_URL") {
+            self.api_base_url = url;
+        }
+        if let Some(uri) = var("DISCORD_REDIRECT_URI") {
+            self.redirect_uri = uri;
+        }
+        self
+    }
+
+    pub fn add_profile(&mut self, name: &str) -> Result<(), AuthError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(profile_error("A profile needs a name.".to_string()));
+        }
+        if self.profiles.iter().any(|p| p.name == name) {
+            return Err(profile_error(format!("The profile {name} already exists.")));
+        }
+        self.profiles.push(Profile::new(name));
+        Ok(())
+    }
+
+    pub fn select_profile(&mut self, name: &str) -> Result<(), AuthError> {
+        if !self.profiles.iter().any(|p| p.name == name) {
+            return Err(profile_error(format!("There is no profile {name}.")));
+        }
+        self.profile = name.to_string();
+        Ok(())
+    }
+
+    /// The selected profile can't be removed, switch to another one first.
+    pub fn remove_profile(&mut self, name: &str) -> Result<(), AuthError> {
+        if self.profile == name {
+            return Err(profile_error(format!(
+                "The profile {name} is in use, switch to another one first."
+            )));
+        }
+        let count = self.profiles.len();
+        self.profiles.retain(|p| p.name != name);
+        if self.profiles.len() == count {
+            return Err(profile_error(format!("There is no profile {name}.")));
+        }
+        Ok(())
+    }
+
+    /// Adds the channel, or renames it when it already is a favorite.
+    pub fn add_favorite(&mut self, channel: FavoriteChannel) {
+        match self
+            .favorite_channels
+            .iter_mut()
+            .find(|c| c.id == channel.id)
+        {
+            Some(existing) => *existing = channel,
+            None => self.favorite_channels.push(channel),
+        }
+    }
+
+    pub fn remove_favorite(&mut self, channel_id: &str) {
+        self.favorite_channels.retain(|c| c.id != channel_id);
+    }
+}
+
+fn profile_error(message: String) -> AuthError {
+    AuthError {
+        error_type: AuthErrorType::Profile,
+        message,
+    }
+}
+
+/// The config as it is in the file, without the environment overrides.
+fn load_config() -> Result<Config, confy::ConfyError> {
+    confy::load("discord-vc-status", "discord-vc-status")
 }
 
 pub fn get_config() -> Result<Config, confy::ConfyError> {
-    match confy::load("discord-vc-status", "discord-vc-status") {
-        Ok(r) => Ok(r),
-        Err(e) => Err(e),
+    Ok(load_config()?.with_env_overrides())
+}
+
+/// Changes the config file with `change`, nothing is written when it fails.
+pub fn update_config<T>(
+    change: impl FnOnce(&mut Config) -> Result<T, AuthError>,
+) -> Result<T, AuthError> {
+    let mut config = load_config().map_err(|err| AuthError {
+        error_type: AuthErrorType::ConfigRead,
+        message: err.to_string(),
+    })?;
+    let result = change(&mut config)?;
+    set_config(config).map_err(|err| AuthError {
+        error_type: AuthErrorType::ConfigSave,
+        message: err.to_string(),
+    })?;
+    Ok(result)
+}
+
+/// Caches who `profile` authenticated as, the file is only written when that changed.
+pub fn remember_user(profile: &str, user: &User) -> Result<(), AuthError> {
+    let known = get_config()
+        .ok()
+        .and_then(|c| c.profiles.into_iter().find(|p| p.name == profile))
+        .and_then(|p| p.user);
+    if known.as_ref() == Some(user) {
+        return Ok(());
     }
+    update_config(|config| {
+        match config.profiles.iter_mut().find(|p| p.name == profile) {
+            Some(p) => p.user = Some(user.clone()),
+            None => config.profiles.push(Profile {
+                name: profile.to_string(),
+                user: Some(user.clone()),
+            }),
+        }
+        Ok(())
+    })
 }
 
 pub fn set_config(config: Config) -> Result<(), confy::ConfyError> {
@@ -21,31 +243,117 @@ pub fn set_config(config: Config) -> Result<(), confy::ConfyError> {
     }
 }
 
-pub fn save_refresh_token(refresh_token: String) -> Result<(), keyring::Error> {
-    let entry = match Entry::new("discord-vc-status", "refresh_token") {
-        Ok(e) => e,
-        Err(err) => {
-            return Err(err);
+/// What is kept in the keyring next to the refresh token.
+/// Older versions stored the bare refresh token, which still reads fine.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredTokens {
+    pub refresh_token: String,
+    /// Unix time in seconds the last access token expires at, 0 when unknown.
+    #[serde(default)]
+    pub expires_at: u64,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl From<&TokenData> for StoredTokens {
+    fn from(tokens: &TokenData) -> Self {
+        let expires_at = match tokens.expires_at.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => 0,
+        };
+        Self {
+            refresh_token: tokens.refresh_token.clone(),
+            expires_at,
+            scopes: tokens.scopes.clone(),
         }
-    };
-    if let Err(err) = entry.set_password(&refresh_token) {
-        return Err(err);
     }
-    Ok(())
 }
 
-pub fn get_refresh_token() -> Result<String, keyring::Error> {
-    let entry = match Entry::new("discord-vc-status", "refresh_token") {
-        Ok(e) => e,
-        Err(err) => {
-            return Err(err);
-        }
-    };
-    let token = match entry.get_password() {
-        Ok(t) => t,
-        Err(err) => {
-            return Err(err);
-        }
+/// Every profile has an entry of its own.
+fn token_key(profile: &str) -> String {
+    if profile == DEFAULT_PROFILE {
+        "refresh_token".to_string()
+    } else {
+        format!("refresh_token.{profile}")
+    }
+}
+
+pub fn save_tokens(profile: &str, tokens: &TokenData) -> Result<(), SecretError> {
+    let stored = serde_json::to_string(&StoredTokens::from(tokens))
+        .map_err(|err| SecretError::Failed(err.to_string()))?;
+    secret::store().set(&token_key(profile), &stored)
+}
+
+/// `None` when the profile has no refresh token.
+pub fn get_stored_tokens(profile: &str) -> Result<Option<StoredTokens>, SecretError> {
+    let Some(stored) = secret::store().get(&token_key(profile))? else {
+        return Ok(None);
     };
-    Ok(token)
+    match serde_json::from_str(&stored) {
+        Ok(t) => Ok(Some(t)),
+        // written by an older version
+        Err(_) => Ok(Some(StoredTokens {
+            refresh_token: stored,
+            expires_at: 0,
+            scopes: Vec::new(),
+        })),
+    }
+}
+
+pub fn delete_tokens(profile: &str) -> Result<(), SecretError> {
+    secret::store().delete(&token_key(profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_the_endpoints() {
+        let config = Config::default().with_overrides(|name| match name {
+            "DISCORD_API_BASE_URL" => Some("http://127.0.0.1:9000/api".to_string()),
+            _ => None,
+        });
+        assert_eq!(config.api_base_url, "http://127.0.0.1:9000/api");
+        assert_eq!(config.redirect_uri, DEFAULT_REDIRECT_URI);
+
+        let config = Config::default().with_overrides(|name| match name {
+            "DISCORD_REDIRECT_URI" => Some("https://example.com/callback".to_string()),
+            _ => None,
+        });
+        assert_eq!(config.api_base_url, DEFAULT_API_BASE_URL);
+        assert_eq!(config.redirect_uri, "https://example.com/callback");
+    }
+
+    #[test]
+    fn profiles_are_added_selected_and_removed() {
+        let mut config = Config::default();
+        assert!(config.add_profile("work").is_ok());
+        assert!(config.add_profile(" work ").is_err());
+        assert!(config.add_profile("").is_err());
+        assert!(config.select_profile("personal").is_err());
+
+        assert!(config.select_profile("work").is_ok());
+        assert_eq!(config.profile, "work");
+        assert!(config.remove_profile("work").is_err());
+        assert!(config.remove_profile(DEFAULT_PROFILE).is_ok());
+        assert!(config.remove_profile(DEFAULT_PROFILE).is_err());
+        assert_eq!(config.profiles, [Profile::new("work")]);
+    }
+
+    #[test]
+    fn favorites_are_kept_once() {
+        let favorite = |name: &str| FavoriteChannel {
+            id: "300".to_string(),
+            name: name.to_string(),
+            guild_id: Some("400".to_string()),
+        };
+        let mut config = Config::default();
+        config.add_favorite(favorite("standup"));
+        config.add_favorite(favorite("daily standup"));
+        assert_eq!(config.favorite_channels, [favorite("daily standup")]);
+
+        config.remove_favorite("300");
+        assert!(config.favorite_channels.is_empty());
+    }
 }
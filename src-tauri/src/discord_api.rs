@@ -1 +1,4 @@
 pub mod api_client;
+pub mod exchanger;
+pub mod pkce;
+pub mod retry;
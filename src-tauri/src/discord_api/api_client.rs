@@ -1,14 +1,23 @@
-use dotenvy_macro::dotenv;
-use reqwest::{
-    header::{self, HeaderMap},
-    Client,
-};
+use std::time::{Duration, SystemTime};
+
 use serde::{Deserialize, Serialize};
 
-use crate::ipc::auth::{AuthError, AuthErrorType};
+use crate::{config::Config, ipc::auth::AuthError};
+
+use super::{
+    exchanger::{
+        BrokerExchanger, ClientSecretExchanger, OAuthEndpoint, PublicClientExchanger,
+        TokenExchanger,
+    },
+    pkce::Pkce,
+    retry::RetryPolicy,
+};
+
+pub const DEFAULT_API_BASE_URL: &str = "https://discord.com/api";
+pub const DEFAULT_REDIRECT_URI: &str = "http://localhost";
 
 #[derive(Debug, Deserialize, Serialize)]
-struct DiscordTokenResponse {
+pub struct DiscordTokenResponse {
     access_token: String,
     token_type: String,
     expires_in: i32,
@@ -16,122 +25,82 @@ struct DiscordTokenResponse {
     scope: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct TokenData {
     pub access_token: String,
     pub refresh_token: String,
+    /// When discord stops accepting the access token.
+    pub expires_at: SystemTime,
+    pub scopes: Vec<String>,
+}
+
+impl From<DiscordTokenResponse> for TokenData {
+    fn from(response: DiscordTokenResponse) -> Self {
+        let expires_in = Duration::from_secs(response.expires_in.max(0) as u64);
+        Self {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            expires_at: SystemTime::now() + expires_in,
+            scopes: response.scope.split(' ').map(str::to_string).collect(),
+        }
+    }
 }
 
 pub struct DiscordAPIClient {
-    client: Client,
+    exchanger: Box<dyn TokenExchanger>,
+    pkce: bool,
 }
 
 impl DiscordAPIClient {
     pub fn new() -> Self {
-        Self {
-            client: reqwest::Client::new(),
-        }
+        Self::with_exchanger(
+            Box::new(ClientSecretExchanger::from_env(OAuthEndpoint::default())),
+            false,
+        )
     }
 
-    pub async fn fetch_discord_token(&self, code: &str) -> Result<TokenData, AuthError> {
-        let url = "https://discord.com/api/oauth2/token";
-        let mut headers = HeaderMap::new();
-        let client_id = dotenv!("CLIENT_ID");
-        let client_secret = dotenv!("CLIENT_SECRET");
-        headers.insert(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("application/x-www-form-urlencoded"),
-        );
-        let body =
-            format!("grant_type=authorization_code&code={code}&redirect_uri=http://localhost");
-        let response = match self
-            .client
-            .post(url)
-            .headers(headers)
-            .basic_auth(client_id, Some(client_secret))
-            .body(body)
-            .send()
-            .await
-        {
-            Ok(r) => r,
-            Err(err) => {
-                return Err(AuthError {
-                    error_type: AuthErrorType::TokenFetch,
-                    message: format!("Failed to fetch token.\n{}", err.to_string()),
-                });
-            }
-        };
+    /// `pkce` makes every authorization use PKCE.
+    pub fn with_exchanger(exchanger: Box<dyn TokenExchanger>, pkce: bool) -> Self {
+        Self { exchanger, pkce }
+    }
 
-        let response_json: DiscordTokenResponse = match response.text().await {
-            Ok(t) => serde_json::from_str(&t).unwrap(),
-            Err(err) => {
-                return Err(AuthError {
-                    error_type: AuthErrorType::TokenFetch,
-                    message: format!("Failed to decode token response.\n{}", err.to_string()),
-                });
+    /// Goes through the token broker when one is configured. Otherwise straight
+    /// to discord, as a public client with PKCE or with the client secret.
+    pub fn from_config(config: &Config) -> Self {
+        let endpoint = OAuthEndpoint::from_config(config);
+        let retry = RetryPolicy::from_config(config);
+        let exchanger: Box<dyn TokenExchanger> = match &config.token_broker_url {
+            Some(url) => {
+                Box::new(BrokerExchanger::new(url.clone(), endpoint.redirect_uri).with_retry(retry))
             }
+            None if config.pkce => Box::new(PublicClientExchanger::new(endpoint).with_retry(retry)),
+            None => Box::new(ClientSecretExchanger::from_env(endpoint).with_retry(retry)),
         };
+        Self::with_exchanger(exchanger, config.pkce)
+    }
+
+    /// A fresh verifier and challenge for the next authorization, when it uses PKCE.
+    pub fn pkce(&self) -> Option<Pkce> {
+        self.pkce.then(Pkce::new)
+    }
 
-        Ok(TokenData {
-            access_token: response_json.access_token,
-            refresh_token: response_json.refresh_token,
-        })
+    pub async fn fetch_discord_token(
+        &self,
+        code: &str,
+        pkce: Option<&Pkce>,
+    ) -> Result<TokenData, AuthError> {
+        let verifier = pkce.map(|p| p.verifier.as_str());
+        self.exchanger.exchange_code(code, verifier).await
     }
 
     pub async fn refresh_discord_token(
         &self,
         refresh_token: String,
     ) -> Result<TokenData, AuthError> {
-        let url = "https://discord.com/api/oauth2/token";
-        let mut headers = HeaderMap::new();
-        let client_id = dotenv!("CLIENT_ID");
-        let client_secret = dotenv!("CLIENT_SECRET");
-        headers.insert(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("application/x-www-form-urlencoded"),
-        );
-        let body = format!("grant_type=refresh_token&refresh_token={refresh_token}");
-        let response = match self
-            .client
-            .post(url)
-            .headers(headers)
-            .basic_auth(client_id, Some(client_secret))
-            .body(body)
-            .send()
-            .await
-        {
-            Ok(r) => r,
-            Err(err) => {
-                return Err(AuthError {
-                    error_type: AuthErrorType::RefreshToken,
-                    message: format!("Failed to refresh token.\n{}", err.to_string()),
-                });
-            }
-        };
-
-        match response.error_for_status_ref() {
-            Ok(_) => (),
-            Err(err) => {
-                return Err(AuthError {
-                    error_type: AuthErrorType::RefreshToken,
-                    message: format!("Failed to refresh token.\n{}", err.to_string()),
-                });
-            }
-        }
-
-        let response_json: DiscordTokenResponse = match response.text().await {
-            Ok(t) => serde_json::from_str(&t).unwrap(),
-            Err(err) => {
-                return Err(AuthError {
-                    error_type: AuthErrorType::RefreshToken,
-                    message: format!("Failed to decode token response.\n{}", err.to_string()),
-                });
-            }
-        };
+        self.exchanger.refresh_token(&refresh_token).await
+    }
 
-        Ok(TokenData {
-            access_token: response_json.access_token,
-            refresh_token: response_json.refresh_token,
-        })
+    pub async fn revoke_discord_token(&self, refresh_token: String) -> Result<(), AuthError> {
+        self.exchanger.revoke_token(&refresh_token).await
     }
 }
@@ -0,0 +1,777 @@
+use std::{future::Future, pin::Pin};
+
+use dotenvy_macro::dotenv;
+use reqwest::{Client, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    config::Config,
+    ipc::auth::{AuthError, AuthErrorType},
+};
+
+use super::{
+    api_client::{DiscordTokenResponse, TokenData, DEFAULT_API_BASE_URL, DEFAULT_REDIRECT_URI},
+    retry::{self, Idempotency, RetryPolicy},
+};
+
+/// Where codes are exchanged, and the redirect URI they were issued for.
+#[derive(Clone, Debug)]
+pub struct OAuthEndpoint {
+    pub api_base_url: String,
+    pub redirect_uri: String,
+}
+
+impl OAuthEndpoint {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            api_base_url: config.api_base_url.clone(),
+            redirect_uri: config.redirect_uri.clone(),
+        }
+    }
+
+    fn token_url(&self) -> String {
+        format!("{}/oauth2/token", self.api_base_url.trim_end_matches('/'))
+    }
+
+    fn revoke_url(&self) -> String {
+        format!("{}/revoke", self.token_url())
+    }
+}
+
+impl Default for OAuthEndpoint {
+    fn default() -> Self {
+        Self {
+            api_base_url: DEFAULT_API_BASE_URL.to_string(),
+            redirect_uri: DEFAULT_REDIRECT_URI.to_string(),
+        }
+    }
+}
+
+pub type TokenFuture<'a> = Pin<Box<dyn Future<Output = Result<TokenData, AuthError>> + Send + 'a>>;
+pub type RevokeFuture<'a> = Pin<Box<dyn Future<Output = Result<(), AuthError>> + Send + 'a>>;
+
+/// Swaps an OAuth2 code or a refresh token for a new set of tokens.
+pub trait TokenExchanger: Send + Sync {
+    /// `code_verifier` is given when the code was authorized with PKCE.
+    fn exchange_code<'a>(
+        &'a self,
+        code: &'a str,
+        code_verifier: Option<&'a str>,
+    ) -> TokenFuture<'a>;
+    fn refresh_token<'a>(&'a self, refresh_token: &'a str) -> TokenFuture<'a>;
+    /// Revokes the refresh token, along with the access tokens issued with it.
+    fn revoke_token<'a>(&'a self, refresh_token: &'a str) -> RevokeFuture<'a>;
+}
+
+fn code_form<'a>(
+    code: &'a str,
+    code_verifier: Option<&'a str>,
+    endpoint: &'a OAuthEndpoint,
+) -> Vec<(&'a str, &'a str)> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &endpoint.redirect_uri),
+    ];
+    if let Some(verifier) = code_verifier {
+        form.push(("code_verifier", verifier));
+    }
+    form
+}
+
+fn revoke_form(refresh_token: &str) -> [(&str, &str); 2] {
+    [
+        ("token", refresh_token),
+        ("token_type_hint", "refresh_token"),
+    ]
+}
+
+/// Talks to discord directly with the client secret.
+/// The secret is read from the environment (or `.env`) when the app starts,
+/// so it never ends up in the binary.
+pub struct ClientSecretExchanger {
+    client: Client,
+    endpoint: OAuthEndpoint,
+    client_secret: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl ClientSecretExchanger {
+    pub fn from_env(endpoint: OAuthEndpoint) -> Self {
+        Self {
+            client: Client::new(),
+            endpoint,
+            client_secret: dotenvy::var("CLIENT_SECRET").ok(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn request(
+        &self,
+        url: String,
+        form: &[(&str, &str)],
+        error_type: AuthErrorType,
+        idempotency: Idempotency,
+    ) -> Result<String, AuthError> {
+        let Some(client_secret) = &self.client_secret else {
+            return Err(AuthError {
+                error_type,
+                message: "CLIENT_SECRET is not set, configure a token broker or PKCE instead."
+                    .to_string(),
+            });
+        };
+        let response = retry::send(&self.retry, idempotency, || {
+            self.client
+                .post(&url)
+                .basic_auth(dotenv!("CLIENT_ID"), Some(client_secret))
+                .form(form)
+        })
+        .await;
+        read_body(response, error_type).await
+    }
+}
+
+impl TokenExchanger for ClientSecretExchanger {
+    fn exchange_code<'a>(
+        &'a self,
+        code: &'a str,
+        code_verifier: Option<&'a str>,
+    ) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let form = code_form(code, code_verifier, &self.endpoint);
+            let url = self.endpoint.token_url();
+            decode_tokens(
+                self.request(
+                    url,
+                    &form,
+                    AuthErrorType::TokenFetch,
+                    Idempotency::NonIdempotent,
+                )
+                .await?,
+            )
+        })
+    }
+
+    fn refresh_token<'a>(&'a self, refresh_token: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let form = [
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ];
+            let url = self.endpoint.token_url();
+            decode_tokens(
+                self.request(
+                    url,
+                    &form,
+                    AuthErrorType::RefreshToken,
+                    Idempotency::Idempotent,
+                )
+                .await?,
+            )
+        })
+    }
+
+    fn revoke_token<'a>(&'a self, refresh_token: &'a str) -> RevokeFuture<'a> {
+        Box::pin(async move {
+            let url = self.endpoint.revoke_url();
+            let form = revoke_form(refresh_token);
+            self.request(url, &form, AuthErrorType::Revoke, Idempotency::Idempotent)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Talks to discord directly as a public client, without any secret.
+/// Codes have to be authorized with PKCE so discord can check the verifier instead.
+pub struct PublicClientExchanger {
+    client: Client,
+    endpoint: OAuthEndpoint,
+    retry: RetryPolicy,
+}
+
+impl PublicClientExchanger {
+    pub fn new(endpoint: OAuthEndpoint) -> Self {
+        Self {
+            client: Client::new(),
+            endpoint,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn request(
+        &self,
+        url: String,
+        form: &[(&str, &str)],
+        error_type: AuthErrorType,
+        idempotency: Idempotency,
+    ) -> Result<String, AuthError> {
+        let mut form = form.to_vec();
+        form.push(("client_id", dotenv!("CLIENT_ID")));
+        let response = retry::send(&self.retry, idempotency, || {
+            self.client.post(&url).form(&form)
+        })
+        .await;
+        read_body(response, error_type).await
+    }
+}
+
+impl TokenExchanger for PublicClientExchanger {
+    fn exchange_code<'a>(
+        &'a self,
+        code: &'a str,
+        code_verifier: Option<&'a str>,
+    ) -> TokenFuture<'a> {
+        Box::pin(async move {
+            if code_verifier.is_none() {
+                return Err(AuthError {
+                    error_type: AuthErrorType::TokenFetch,
+                    message: "A public client can only exchange codes authorized with PKCE."
+                        .to_string(),
+                });
+            }
+            let form = code_form(code, code_verifier, &self.endpoint);
+            let url = self.endpoint.token_url();
+            decode_tokens(
+                self.request(
+                    url,
+                    &form,
+                    AuthErrorType::TokenFetch,
+                    Idempotency::NonIdempotent,
+                )
+                .await?,
+            )
+        })
+    }
+
+    fn refresh_token<'a>(&'a self, refresh_token: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let form = [
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ];
+            let url = self.endpoint.token_url();
+            decode_tokens(
+                self.request(
+                    url,
+                    &form,
+                    AuthErrorType::RefreshToken,
+                    Idempotency::Idempotent,
+                )
+                .await?,
+            )
+        })
+    }
+
+    fn revoke_token<'a>(&'a self, refresh_token: &'a str) -> RevokeFuture<'a> {
+        Box::pin(async move {
+            let url = self.endpoint.revoke_url();
+            let form = revoke_form(refresh_token);
+            self.request(url, &form, AuthErrorType::Revoke, Idempotency::Idempotent)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Hands the code or refresh token to a self-hosted broker that holds the client secret,
+/// e.g. the `token-broker` in this workspace.
+pub struct BrokerExchanger {
+    client: Client,
+    url: String,
+    redirect_uri: String,
+    retry: RetryPolicy,
+}
+
+impl BrokerExchanger {
+    pub fn new(url: String, redirect_uri: String) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            redirect_uri,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+impl TokenExchanger for BrokerExchanger {
+    fn exchange_code<'a>(
+        &'a self,
+        code: &'a str,
+        code_verifier: Option<&'a str>,
+    ) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let body = json!({
+                "grant_type": "authorization_code",
+                "code": code,
+                "redirect_uri": self.redirect_uri,
+                "code_verifier": code_verifier
+            });
+            let response = retry::send(&self.retry, Idempotency::NonIdempotent, || {
+                self.client.post(&self.url).json(&body)
+            })
+            .await;
+            decode_tokens(read_body(response, AuthErrorType::TokenFetch).await?)
+        })
+    }
+
+    fn refresh_token<'a>(&'a self, refresh_token: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let body = json!({
+                "grant_type": "refresh_token",
+                "refresh_token": refresh_token
+            });
+            let response = retry::send(&self.retry, Idempotency::Idempotent, || {
+                self.client.post(&self.url).json(&body)
+            })
+            .await;
+            decode_tokens(read_body(response, AuthErrorType::RefreshToken).await?)
+        })
+    }
+
+    /// The broker revokes at `<url>/revoke`.
+    fn revoke_token<'a>(&'a self, refresh_token: &'a str) -> RevokeFuture<'a> {
+        Box::pin(async move {
+            let url = format!("{}/revoke", self.url.trim_end_matches('/'));
+            let body = json!({ "token": refresh_token });
+            let response = retry::send(&self.retry, Idempotency::Idempotent, || {
+                self.client.post(&url).json(&body)
+            })
+            .await;
+            read_body(response, AuthErrorType::Revoke).await?;
+            Ok(())
+        })
+    }
+}
+
+/// The body of an OAuth2 error response (RFC 6749 5.2).
+#[derive(Deserialize)]
+struct OAuthErrorBody {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Turns a non-2xx token response into the matching error.
+/// `error_type` is used for errors that don't have a variant of their own.
+fn oauth_error(status: StatusCode, body: &str, error_type: AuthErrorType) -> AuthError {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return AuthError {
+            error_type: AuthErrorType::RateLimited,
+            message: format!("Discord is rate limiting token requests.\n{}", body),
+        };
+    }
+    let Ok(error) = serde_json::from_str::<OAuthErrorBody>(body) else {
+        return AuthError {
+            error_type,
+            message: format!("Token request failed with {}.\n{}", status, body),
+        };
+    };
+    let error_type = match error.error.as_str() {
+        "invalid_grant" => AuthErrorType::InvalidGrant,
+        "invalid_client" | "unauthorized_client" => AuthErrorType::InvalidClient,
+        _ => error_type,
+    };
+    AuthError {
+        error_type,
+        message: format!(
+            "Token request failed with {}.\n{}",
+            error.error,
+            error.error_description.unwrap_or_default()
+        ),
+    }
+}
+
+/// The body of a successful response, or the error discord answered with.
+async fn read_body(
+    response: reqwest::Result<Response>,
+    error_type: AuthErrorType,
+) -> Result<String, AuthError> {
+    let response = match response {
+        Ok(r) => r,
+        Err(err) => {
+            return Err(AuthError {
+                error_type,
+                message: format!("Failed to reach the token endpoint.\n{}", err),
+            });
+        }
+    };
+    let status = response.status();
+    let text = match response.text().await {
+        Ok(t) => t,
+        Err(err) => {
+            return Err(AuthError {
+                error_type,
+                message: format!("Failed to read the token endpoint's response.\n{}", err),
+            });
+        }
+    };
+    if !status.is_success() {
+        return Err(oauth_error(status, &text, error_type));
+    }
+    Ok(text)
+}
+
+fn decode_tokens(text: String) -> Result<TokenData, AuthError> {
+    match serde_json::from_str::<DiscordTokenResponse>(&text) {
+        Ok(r) => Ok(TokenData::from(r)),
+        Err(err) => Err(AuthError {
+            error_type: AuthErrorType::Decode,
+            message: format!("Failed to decode token response.\n{}", err),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord_api::pkce::Pkce;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tauri::async_runtime::{block_on, spawn};
+    use token_broker::{http, BrokerConfig};
+    use tokio::net::TcpListener;
+
+    /// Stands in for discord's API, returning its base URL and the requests it got.
+    async fn discord() -> (String, Arc<Mutex<Vec<http::Request>>>) {
+        discord_replying(Vec::new()).await
+    }
+
+    type Reply = (u16, &'static [(&'static str, &'static str)], String);
+
+    /// Like `discord`, answering with `replies` in order before handing out tokens.
+    async fn discord_replying(replies: Vec<Reply>) -> (String, Arc<Mutex<Vec<http::Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let requests = Arc::clone(&received);
+        spawn(async move {
+            let mut replies = replies.into_iter();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = http::read_request(&mut stream).await.unwrap();
+                requests.lock().unwrap().push(request);
+                let (status, headers, body) = replies.next().unwrap_or_else(|| {
+                    let tokens = json!({
+                        "access_token": "access",
+                        "token_type": "Bearer",
+                        "expires_in": 604800,
+                        "refresh_token": "refresh",
+                        "scope": "rpc identify"
+                    });
+                    (200, &[], tokens.to_string())
+                });
+                http::write_response(&mut stream, status, headers, body.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn body(request: &http::Request) -> &str {
+        std::str::from_utf8(&request.body).unwrap()
+    }
+
+    #[test]
+    fn public_client_exchanges_codes_with_the_verifier() {
+        block_on(async {
+            let (api_base_url, received) = discord().await;
+            let config = Config {
+                api_base_url,
+                redirect_uri: "https://example.com/callback".to_string(),
+                ..Default::default()
+            };
+            let exchanger = PublicClientExchanger::new(OAuthEndpoint::from_config(&config));
+            let pkce = Pkce::new();
+
+            let Ok(tokens) = exchanger.exchange_code("code", Some(&pkce.verifier)).await else {
+                panic!("the code exchange failed");
+            };
+            assert_eq!(tokens.refresh_token, "refresh");
+
+            let received = received.lock().unwrap();
+            let request = &received[0];
+            assert_eq!(request.path, "/api/oauth2/token");
+            // no secret to authenticate with
+            assert_eq!(request.header("authorization"), None);
+            assert_eq!(
+                body(request),
+                format!(
+                    "grant_type=authorization_code&code=code\
+                     &redirect_uri=https%3A%2F%2Fexample.com%2Fcallback\
+                     &code_verifier={}&client_id={}",
+                    pkce.verifier,
+                    dotenv!("CLIENT_ID")
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn public_client_revokes_refresh_tokens() {
+        block_on(async {
+            let (api_base_url, received) = discord().await;
+            let endpoint = OAuthEndpoint {
+                api_base_url,
+                ..Default::default()
+            };
+            let exchanger = PublicClientExchanger::new(endpoint);
+
+            assert!(exchanger.revoke_token("refresh").await.is_ok());
+
+            let received = received.lock().unwrap();
+            assert_eq!(received[0].path, "/api/oauth2/token/revoke");
+            assert_eq!(
+                body(&received[0]),
+                format!(
+                    "token=refresh&token_type_hint=refresh_token&client_id={}",
+                    dotenv!("CLIENT_ID")
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn oauth_errors_are_parsed() {
+        let invalid_grant = json!({
+            "error": "invalid_grant",
+            "error_description": "Invalid \"refresh_token\" in request."
+        });
+        let invalid_client = json!({ "error": "invalid_client" });
+        let rate_limited = json!({ "message": "You are being rate limited.", "retry_after": 1.5 });
+        let replies: Vec<Reply> = vec![
+            (400, &[], invalid_grant.to_string()),
+            (401, &[], invalid_client.to_string()),
+            (429, &[], rate_limited.to_string()),
+            (502, &[], "<html>Bad Gateway</html>".to_string()),
+            (200, &[], "{}".to_string()),
+        ];
+        block_on(async {
+            let (api_base_url, _) = discord_replying(replies).await;
+            let exchanger = PublicClientExchanger::new(OAuthEndpoint {
+                api_base_url,
+                ..Default::default()
+            })
+            .with_retry(RetryPolicy {
+                max_attempts: 1,
+                ..Default::default()
+            });
+
+            let mut error_types = Vec::new();
+            for _ in 0..5 {
+                match exchanger.refresh_token("refresh").await {
+                    Ok(_) => panic!("an error response was taken for tokens"),
+                    Err(err) => error_types.push(serde_json::to_value(err.error_type).unwrap()),
+                }
+            }
+            assert_eq!(
+                error_types,
+                [
+                    "InvalidGrant",
+                    "InvalidClient",
+                    "RateLimited",
+                    "RefreshToken",
+                    "Decode"
+                ]
+            );
+        });
+    }
+
+    fn quick_retries() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            max_total_wait: Duration::from_secs(2),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rate_limits_and_outages_are_retried() {
+        let replies: Vec<Reply> = vec![
+            (
+                429,
+                &[("Retry-After", "0"), ("X-RateLimit-Remaining", "0")],
+                "{}".to_string(),
+            ),
+            (503, &[], String::new()),
+        ];
+        block_on(async {
+            let (api_base_url, received) = discord_replying(replies).await;
+            let endpoint = OAuthEndpoint {
+                api_base_url,
+                ..Default::default()
+            };
+            let exchanger = PublicClientExchanger::new(endpoint).with_retry(quick_retries());
+
+            let Ok(tokens) = exchanger.refresh_token("refresh").await else {
+                panic!("the refresh was not retried");
+            };
+            assert_eq!(tokens.refresh_token, "refresh");
+            assert_eq!(received.lock().unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn code_exchanges_are_only_retried_when_discord_surely_did_not_use_the_code() {
+        let replies: Vec<Reply> = vec![
+            (429, &[("Retry-After", "0")], "{}".to_string()),
+            (502, &[], String::new()),
+        ];
+        block_on(async {
+            let (api_base_url, received) = discord_replying(replies).await;
+            let endpoint = OAuthEndpoint {
+                api_base_url,
+                ..Default::default()
+            };
+            let exchanger = PublicClientExchanger::new(endpoint).with_retry(quick_retries());
+
+            let Err(err) = exchanger.exchange_code("code", Some("verifier")).await else {
+                panic!("the code was sent again after a 502");
+            };
+            assert!(matches!(err.error_type, AuthErrorType::TokenFetch));
+            assert_eq!(received.lock().unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn empty_rate_limit_buckets_are_waited_out() {
+        let tokens = json!({
+            "access_token": "access",
+            "token_type": "Bearer",
+            "expires_in": 604800,
+            "refresh_token": "refresh",
+            "scope": "rpc identify"
+        });
+        let replies: Vec<Reply> = vec![(
+            200,
+            &[
+                ("X-RateLimit-Remaining", "0"),
+                ("X-RateLimit-Reset-After", "0.3"),
+            ],
+            tokens.to_string(),
+        )];
+        block_on(async {
+            let (api_base_url, received) = discord_replying(replies).await;
+            let endpoint = OAuthEndpoint {
+                api_base_url,
+                ..Default::default()
+            };
+            let exchanger = PublicClientExchanger::new(endpoint).with_retry(quick_retries());
+
+            assert!(exchanger.refresh_token("refresh").await.is_ok());
+            let started = std::time::Instant::now();
+            assert!(exchanger.refresh_token("refresh").await.is_ok());
+            assert!(started.elapsed() >= Duration::from_millis(250));
+            assert_eq!(received.lock().unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn retries_give_up_at_the_caps() {
+        let replies: Vec<Reply> = vec![
+            // longer than the whole wait we allow
+            (
+                429,
+                &[("X-RateLimit-Reset-After", "3600.5")],
+                "{}".to_string(),
+            ),
+            (503, &[], String::new()),
+            (503, &[], String::new()),
+            (503, &[], String::new()),
+        ];
+        block_on(async {
+            let (api_base_url, received) = discord_replying(replies).await;
+            let endpoint = OAuthEndpoint {
+                api_base_url,
+                ..Default::default()
+            };
+            let exchanger = PublicClientExchanger::new(endpoint).with_retry(quick_retries());
+
+            let Err(err) = exchanger.refresh_token("refresh").await else {
+                panic!("waited out an hour long rate limit");
+            };
+            assert!(matches!(err.error_type, AuthErrorType::RateLimited));
+            assert_eq!(received.lock().unwrap().len(), 1);
+
+            let Err(err) = exchanger.refresh_token("refresh").await else {
+                panic!("retried past the attempts");
+            };
+            assert!(matches!(err.error_type, AuthErrorType::RefreshToken));
+            assert_eq!(received.lock().unwrap().len(), 4);
+        });
+    }
+
+    #[test]
+    fn public_client_needs_pkce() {
+        let exchanger = PublicClientExchanger::new(OAuthEndpoint::default());
+        assert!(block_on(exchanger.exchange_code("code", None)).is_err());
+    }
+
+    #[test]
+    fn broker_exchanges_codes_and_refresh_tokens() {
+        block_on(async {
+            let (api_base_url, received) = discord().await;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/token", listener.local_addr().unwrap());
+            spawn(token_broker::serve(
+                listener,
+                BrokerConfig {
+                    client_id: "client".to_string(),
+                    client_secret: "secret".to_string(),
+                    token_url: format!("{api_base_url}/oauth2/token"),
+                },
+            ));
+            let exchanger = BrokerExchanger::new(url, DEFAULT_REDIRECT_URI.to_string());
+
+            let Ok(tokens) = exchanger.exchange_code("code", Some("verifier")).await else {
+                panic!("the code exchange failed");
+            };
+            assert_eq!(tokens.access_token, "access");
+            assert_eq!(tokens.scopes, ["rpc", "identify"]);
+            assert!(exchanger.refresh_token("refresh").await.is_ok());
+
+            let received = received.lock().unwrap();
+            let code = body(&received[0]);
+            assert!(code.starts_with("grant_type=authorization_code&code=code&"));
+            assert!(code.ends_with("&code_verifier=verifier"));
+            assert_eq!(
+                body(&received[1]),
+                "grant_type=refresh_token&refresh_token=refresh"
+            );
+        });
+    }
+
+    #[test]
+    fn missing_client_secret_is_reported() {
+        let exchanger = ClientSecretExchanger {
+            client: Client::new(),
+            endpoint: OAuthEndpoint::default(),
+            client_secret: None,
+            retry: RetryPolicy::default(),
+        };
+        let Err(err) = block_on(exchanger.exchange_code("code", None)) else {
+            panic!("the code was exchanged without a secret");
+        };
+        assert!(matches!(err.error_type, AuthErrorType::TokenFetch));
+    }
+}
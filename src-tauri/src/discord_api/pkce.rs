@@ -0,0 +1,58 @@
+//! Proof Key for Code Exchange (RFC 7636), so a public client can exchange
+//! the OAuth2 code without a client secret.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+
+/// Sent along with the challenge in AUTHORIZE.
+pub const CHALLENGE_METHOD: &str = "S256";
+
+pub struct Pkce {
+    /// Kept secret until the code is exchanged.
+    pub verifier: String,
+    /// Goes into AUTHORIZE.
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn new() -> Self {
+        // 32 random bytes make a 43 character verifier, the shortest one allowed
+        let mut bytes = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .expect("The system random number generator failed");
+        Self::from_verifier(URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    fn from_verifier(verifier: String) -> Self {
+        let challenge = URL_SAFE_NO_PAD.encode(digest(&SHA256, verifier.as_bytes()));
+        Self {
+            verifier,
+            challenge,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_matches_the_rfc_example() {
+        let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string());
+        assert_eq!(
+            pkce.challenge,
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn verifiers_are_random() {
+        let (a, b) = (Pkce::new(), Pkce::new());
+        assert_eq!(a.verifier.len(), 43);
+        assert_ne!(a.verifier, b.verifier);
+    }
+}
@@ -0,0 +1,196 @@
+//! Retries for token requests that failed for reasons that go away on their own:
+//! rate limits, discord's 5xx and connections that never got through. Requests also
+//! wait for the rate limit to reset once discord says it is used up.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::time::{sleep, sleep_until};
+
+use crate::config::Config;
+
+pub const DEFAULT_RETRY_MAX_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Including the first one.
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled for every retry after it.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Gives up instead of waiting longer than this in total,
+    /// also when discord asks for a longer wait.
+    pub max_total_wait: Duration,
+    /// Shared by the requests sent with this policy and its clones.
+    pub rate_limit: Arc<RateLimit>,
+}
+
+/// Whether a request may be sent again after discord may have handled it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Idempotency {
+    /// Also retried after discord's 5xx.
+    Idempotent,
+    /// Only retried when discord surely didn't handle it: after a 429 or when the
+    /// connection never got through. An authorization code is used up once handled.
+    NonIdempotent,
+}
+
+/// Discord's rate limit bucket. Once it is empty, requests wait until it resets
+/// instead of running into a 429.
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    reset_at: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    async fn wait(&self) {
+        let reset_at = self.reset_at.lock().unwrap().take();
+        if let Some(reset_at) = reset_at {
+            sleep_until(reset_at.into()).await;
+        }
+    }
+
+    fn update(&self, headers: &HeaderMap) {
+        let empty = headers
+            .get("x-ratelimit-remaining")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim() == "0");
+        if let (true, Some(reset_after)) = (empty, seconds(headers, "x-ratelimit-reset-after")) {
+            *self.reset_at.lock().unwrap() = Some(Instant::now() + reset_after);
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_total_wait: config.token_retry_max_wait(),
+            ..Default::default()
+        }
+    }
+
+    /// Backoff before the given retry, with the upper half randomized
+    /// so clients that failed together don't come back together.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(jitter())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+            max_total_wait: DEFAULT_RETRY_MAX_WAIT,
+            rate_limit: Arc::default(),
+        }
+    }
+}
+
+/// A number in [0, 1).
+fn jitter() -> f64 {
+    let mut bytes = [0u8; 4];
+    match SystemRandom::new().fill(&mut bytes) {
+        Ok(_) => u32::from_le_bytes(bytes) as f64 / (u32::MAX as f64 + 1.0),
+        Err(_) => 0.5,
+    }
+}
+
+fn is_transient(status: StatusCode, idempotency: Idempotency) -> bool {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => true,
+        StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => idempotency == Idempotency::Idempotent,
+        _ => false,
+    }
+}
+
+fn seconds(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    let value = headers.get(name)?.to_str().ok()?;
+    Duration::try_from_secs_f64(value.trim().parse().ok()?).ok()
+}
+
+/// How long discord asks us to wait, from `Retry-After` or its own
+/// `X-RateLimit-Reset-After`. Only the seconds form of `Retry-After` is read,
+/// discord doesn't send dates.
+fn requested_wait(headers: &HeaderMap) -> Option<Duration> {
+    seconds(headers, "retry-after").or_else(|| seconds(headers, "x-ratelimit-reset-after"))
+}
+
+/// Sends the request built by `request`, again after a while if it failed transiently.
+/// Once the attempts or the wait run out, the last response or error is returned as is.
+pub async fn send(
+    policy: &RetryPolicy,
+    idempotency: Idempotency,
+    request: impl Fn() -> RequestBuilder,
+) -> reqwest::Result<Response> {
+    let mut waited = Duration::ZERO;
+    let mut attempt = 1;
+    loop {
+        policy.rate_limit.wait().await;
+        let result = request().send().await;
+        if let Ok(response) = &result {
+            policy.rate_limit.update(response.headers());
+        }
+        let requested = match &result {
+            Ok(response) if is_transient(response.status(), idempotency) => {
+                requested_wait(response.headers())
+            }
+            // the request never reached discord, so it can't have used up the code
+            Err(err) if err.is_connect() => None,
+            _ => return result,
+        };
+        if attempt >= policy.max_attempts {
+            return result;
+        }
+        let delay = requested.unwrap_or_else(|| policy.backoff(attempt));
+        if waited + delay > policy.max_total_wait {
+            return result;
+        }
+        sleep(delay).await;
+        waited += delay;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_jittered_within_bounds() {
+        let policy = RetryPolicy::default();
+        for retry in 1..10 {
+            let full = policy
+                .base_delay
+                .saturating_mul(2u32.pow(retry - 1))
+                .min(policy.max_delay);
+            let delay = policy.backoff(retry);
+            assert!(delay >= full / 2 && delay <= full, "{delay:?} for {full:?}");
+        }
+    }
+
+    #[test]
+    fn rate_limit_headers_are_read() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(requested_wait(&headers), None);
+        headers.insert("x-ratelimit-reset-after", "1.25".parse().unwrap());
+        assert_eq!(requested_wait(&headers), Some(Duration::from_millis(1250)));
+        headers.insert("retry-after", "3".parse().unwrap());
+        assert_eq!(requested_wait(&headers), Some(Duration::from_secs(3)));
+        headers.insert("retry-after", "-1".parse().unwrap());
+        assert_eq!(requested_wait(&headers), Some(Duration::from_millis(1250)));
+    }
+}
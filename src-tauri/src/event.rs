@@ -0,0 +1,59 @@
+use serde::Serialize;
+use strum_macros::Display;
+use tauri::Window;
+
+use crate::log::log_error;
+
+#[derive(Display)]
+pub enum EventName {
+    #[strum(to_string = "error")]
+    Error,
+    #[strum(to_string = "critical_error")]
+    CriticalError,
+    #[strum(to_string = "vc_select")]
+    VCSelect,
+    #[strum(to_string = "vc_info")]
+    VCInfo,
+    #[strum(to_string = "vc_mute_update")]
+    VCMuteUpdate,
+    #[strum(to_string = "voice_settings")]
+    VoiceSettings,
+    #[strum(to_string = "vc_user")]
+    VCUser,
+    #[strum(to_string = "vc_speak")]
+    VCSpeak,
+    #[strum(to_string = "vc_connection")]
+    VCConnection,
+    #[strum(to_string = "vc_connection_warning")]
+    VCConnectionWarning,
+    #[strum(to_string = "user_id")]
+    UserID,
+    #[strum(to_string = "reconnecting")]
+    Reconnecting,
+    #[strum(to_string = "reconnected")]
+    Reconnected,
+    #[strum(to_string = "connection_state")]
+    ConnectionState,
+    #[strum(to_string = "token_refreshed")]
+    TokenRefreshed,
+    #[strum(to_string = "logged_out")]
+    LoggedOut,
+}
+
+/// Something the ipc flow can report its events to.
+/// In the app this is the main window, in tests it is a recorder.
+pub trait EventEmitter: Clone + Send + Sync + 'static {
+    fn emit_event<S: Serialize + Clone>(&self, event_name: EventName, payload: S);
+}
+
+impl EventEmitter for Window {
+    fn emit_event<S: Serialize + Clone>(&self, event_name: EventName, payload: S) {
+        let event = event_name.to_string();
+        if let Err(err) = &self.emit(&event, payload) {
+            log_error(
+                "Emit Event Error".to_string(),
+                format!("Error while emitting {event_name} event.\n{err}"),
+            );
+        }
+    }
+}
@@ -1,3 +1,12 @@
 pub mod auth;
 pub mod client;
+pub mod discovery;
+#[cfg(all(test, unix))]
+mod mock;
+pub mod protocol;
+pub mod scope;
+pub mod session;
+pub mod socket;
+pub mod state;
 pub mod vc;
+pub mod voice_connection;
@@ -1,10 +1,22 @@
+use std::sync::Arc;
+
 use dotenvy_macro::dotenv;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
-use crate::{config::get_refresh_token, discord_api::api_client::TokenData, log::log_error};
+use crate::{
+    config::{delete_tokens, get_stored_tokens},
+    discord_api::{
+        api_client::{DiscordAPIClient, TokenData},
+        pkce::{self, Pkce},
+    },
+    log::log_error,
+};
 
-use super::client::{ReceiveIPCClient, SendIPCClient};
+use super::{
+    client::{Connection, IpcClient, IpcError, IpcErrorType},
+    protocol::{AuthenticateArgs, AuthenticateData, AuthorizeArgs, Command, Response},
+    scope,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum AuthErrorType {
@@ -14,6 +26,20 @@ pub enum AuthErrorType {
     ConfigSave,
     Decode,
     IpcSend,
+    /// Discord rejected the code or refresh token, it is expired, revoked or used up.
+    InvalidGrant,
+    /// Discord doesn't accept our client credentials.
+    InvalidClient,
+    /// Too many token requests, discord asks us to wait.
+    RateLimited,
+    /// Discord couldn't revoke the refresh token, it is still deleted here.
+    Revoke,
+    /// A profile that doesn't exist, already exists or is in use.
+    Profile,
+    /// The stored tokens were authorized for fewer scopes than the config asks for.
+    MissingScopes,
+    /// The profile has no refresh token yet, it was never authorized or logged out.
+    NoRefreshToken,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -22,10 +48,84 @@ pub struct AuthError {
     pub message: String,
 }
 
-impl SendIPCClient {
-    pub async fn try_reauth(&mut self) -> Result<TokenData, AuthError> {
-        let refresh_token: String = match get_refresh_token() {
-            Ok(c) => c,
+impl AuthError {
+    /// Whether only a new authorization by the user can get us tokens again.
+    pub fn needs_authorization(&self) -> bool {
+        matches!(
+            self.error_type,
+            AuthErrorType::InvalidGrant
+                | AuthErrorType::MissingScopes
+                | AuthErrorType::NoRefreshToken
+        )
+    }
+}
+
+/// Drops a refresh token discord won't take anymore, so it isn't tried again.
+pub fn forget_tokens(profile: &str) {
+    if let Err(err) = delete_tokens(profile) {
+        log_error(
+            "config".to_string(),
+            format!("Could not delete the refresh token.\n{}", err),
+        );
+    }
+}
+
+/// What getting tokens needs from the client. Taken out of it, so the
+/// token requests don't keep the client locked.
+pub struct Credentials {
+    profile: String,
+    scopes: Vec<String>,
+    api_client: Arc<DiscordAPIClient>,
+}
+
+/// A logout whose refresh token is deleted but not yet revoked with discord.
+pub struct Logout {
+    refresh_token: Option<String>,
+    deleted: Result<(), AuthError>,
+    api_client: Arc<DiscordAPIClient>,
+}
+
+impl IpcClient {
+    /// The profile, scopes and API client to get its tokens with.
+    pub fn credentials(&self) -> Credentials {
+        Credentials {
+            profile: self.profile().to_string(),
+            scopes: self.scopes().to_vec(),
+            api_client: Arc::clone(&self.api_client),
+        }
+    }
+
+    /// Closes the connection and deletes the stored refresh token.
+    /// `Logout::revoke` revokes it with discord, the client can be unlocked by then.
+    pub fn logout(&mut self) -> Logout {
+        self.close();
+        let stored = get_stored_tokens(self.profile());
+        let deleted = delete_tokens(self.profile()).map_err(|err| AuthError {
+            error_type: AuthErrorType::ConfigSave,
+            message: format!("Could not delete the refresh token.\n{}", err),
+        });
+        Logout {
+            refresh_token: stored.ok().flatten().map(|t| t.refresh_token),
+            deleted,
+            api_client: Arc::clone(&self.api_client),
+        }
+    }
+}
+
+impl Credentials {
+    fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    pub async fn try_reauth(&self) -> Result<TokenData, AuthError> {
+        let stored = match get_stored_tokens(self.profile()) {
+            Ok(Some(t)) => t,
+            Ok(None) => {
+                return Err(AuthError {
+                    error_type: AuthErrorType::NoRefreshToken,
+                    message: format!("No refresh token is stored for {}.", self.profile()),
+                });
+            }
             Err(err) => {
                 // TODO: replace here with send_auth func
                 log_error(
@@ -39,75 +139,121 @@ impl SendIPCClient {
             }
         };
 
-        let tokens = match self.api_client.refresh_discord_token(refresh_token).await {
+        // older versions didn't store the scopes
+        if !stored.scopes.is_empty() {
+            self.check_scopes(&stored.scopes)?;
+        }
+
+        let tokens = match self
+            .api_client
+            .refresh_discord_token(stored.refresh_token)
+            .await
+        {
             Ok(t) => t,
             Err(err) => {
                 log_error(
                     "fetch token".to_string(),
                     format!("Could not refresh token\n{}", err.message),
                 );
-                return Err(AuthError {
-                    error_type: AuthErrorType::RefreshToken,
-                    message: err.message,
-                });
+                if err.needs_authorization() {
+                    forget_tokens(self.profile());
+                }
+                return Err(err);
             }
         };
+        if !tokens.scopes.is_empty() {
+            self.check_scopes(&tokens.scopes)?;
+        }
 
         Ok(tokens)
     }
 
-    pub async fn send_token(&mut self, access_token: String) -> Result<(), AuthError> {
-        let auth_payload = serde_json::json!({
-            "nonce": Uuid::new_v4().to_string(),
-            "cmd": "AUTHENTICATE",
-            "args": {
-                "access_token": access_token
-            }
-        });
-        if let Err(err) = self.send(auth_payload).await {
-            return Err(AuthError {
-                error_type: AuthErrorType::IpcSend,
-                message: format!("Failed to send access token to ipc.\n{}", err.message),
-            });
+    /// Asking for more scopes than were granted needs a new authorization.
+    fn check_scopes(&self, granted: &[String]) -> Result<(), AuthError> {
+        match scope::first_missing(granted, &self.scopes) {
+            Some(missing) => Err(AuthError {
+                error_type: AuthErrorType::MissingScopes,
+                message: format!("The stored authorization doesn't grant {missing}."),
+            }),
+            None => Ok(()),
         }
-        Ok(())
     }
 }
 
-impl ReceiveIPCClient {
-    pub async fn send_auth(&mut self) -> Result<(), AuthError> {
+impl Logout {
+    /// Revokes the deleted refresh token with discord. The token is gone
+    /// from the keyring even when revoking fails. A failed delete is reported
+    /// either way, it leaves the token on this machine.
+    pub async fn revoke(self) -> Result<(), AuthError> {
+        let revoked = match self.refresh_token {
+            Some(refresh_token) => self.api_client.revoke_discord_token(refresh_token).await,
+            None => Ok(()),
+        };
+        match (self.deleted, revoked) {
+            (Err(deleted), Err(revoked)) => Err(AuthError {
+                error_type: deleted.error_type,
+                message: format!("{}\n{}", deleted.message, revoked.message),
+            }),
+            (Err(err), Ok(())) | (Ok(()), Err(err)) => Err(err),
+            (Ok(()), Ok(())) => Ok(()),
+        }
+    }
+}
+
+impl Connection {
+    /// Asks the user to authorize the app in discord and returns the OAuth2 code.
+    /// The reply only arrives once the user pressed one of the buttons.
+    /// With `pkce` the code can only be exchanged with its verifier.
+    pub async fn authorize(
+        &self,
+        pkce: Option<&Pkce>,
+        scopes: &[String],
+    ) -> Result<String, IpcError> {
         let client_id = dotenv!("CLIENT_ID");
-        let payload = serde_json::json!({
-            "nonce": Uuid::new_v4().to_string(),
-            "cmd": "AUTHORIZE",
-            "args": {
-                "client_id": client_id,
-                "scopes": ["rpc", "identify"]
-            }
+        let command = Command::Authorize(AuthorizeArgs {
+            client_id: client_id.to_string(),
+            scopes: scopes.to_vec(),
+            code_challenge: pkce.map(|p| p.challenge.clone()),
+            code_challenge_method: pkce.map(|_| pkce::CHALLENGE_METHOD.to_string()),
         });
-        if let Err(err) = self.send(payload).await {
-            return Err(AuthError {
-                error_type: AuthErrorType::IpcSend,
-                message: format!("Failed to send authorization request.\n{}", err.message),
-            });
-        }
-        Ok(())
+        // no timeout, the user takes as long as they need
+        let response = match self.send_timeout(command, None).await {
+            Ok(r) => r,
+            Err(err) if matches!(err.error_type, IpcErrorType::EventSend) => {
+                // authorization error (user pressed cancel button)
+                return Err(IpcError {
+                    error_type: IpcErrorType::Authorize,
+                    message: "User cancelled the app authorization.".to_string(),
+                    payload: None,
+                });
+            }
+            Err(err) => return Err(err),
+        };
+        let Response::Authorize(data) = response else {
+            return Err(IpcError::unexpected_response(response));
+        };
+        Ok(data.code)
     }
 
-    pub async fn send_token(&mut self, access_token: String) -> Result<(), AuthError> {
-        let auth_payload = serde_json::json!({
-            "nonce": Uuid::new_v4().to_string(),
-            "cmd": "AUTHENTICATE",
-            "args": {
-                "access_token": access_token
+    pub async fn authenticate(&self, access_token: String) -> Result<AuthenticateData, IpcError> {
+        let command = Command::Authenticate(AuthenticateArgs { access_token });
+        let response = match self.send(command).await {
+            Ok(r) => r,
+            Err(err) if matches!(err.error_type, IpcErrorType::EventSend) => {
+                return Err(IpcError {
+                    error_type: IpcErrorType::Authorize,
+                    message: format!("Failed to send access token to ipc.\n{}", err.message),
+                    payload: None,
+                });
             }
-        });
-        if let Err(err) = self.send(auth_payload).await {
-            return Err(AuthError {
-                error_type: AuthErrorType::IpcSend,
-                message: format!("Failed to send access token to ipc.\n{}", err.message),
-            });
+            Err(err) => return Err(err),
+        };
+        let Response::Authenticate(data) = response else {
+            return Err(IpcError::unexpected_response(response));
+        };
+        if !data.scopes.is_empty() {
+            self.set_scopes(data.scopes.clone());
         }
-        Ok(())
+        Ok(data)
     }
 }
@@ -1,9 +1,32 @@
-use discord_rich_presence::{DiscordIpc, DiscordIpcClient};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    thread,
+    time::Duration,
+};
+
+use dotenvy_macro::dotenv;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tauri::async_runtime::{spawn_blocking, JoinHandle};
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
 use uuid::Uuid;
 
-use crate::discord_api::api_client::DiscordAPIClient;
+use crate::{config::DEFAULT_PROFILE, discord_api::api_client::DiscordAPIClient, log::log_error};
+
+use super::{
+    discovery,
+    protocol::{
+        CloseData, Command, ErrorData, EventType, Message, ReadyData, Response, SubscribeArgs,
+    },
+    scope,
+    socket::{self, Reader, Socket, Writer, OP_CLOSE, OP_FRAME, OP_HANDSHAKE, OP_PING, OP_PONG},
+    state::SharedConnectionState,
+    vc::{SharedVoiceChannelState, VoiceChannelState},
+    voice_connection::VoiceConnectionThresholds,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum IpcErrorType {
@@ -18,6 +41,17 @@ pub enum IpcErrorType {
     EventEncode,
     EventDecode,
     LeaveVC,
+    Timeout,
+    /// Discord closed the connection, the payload is its `CloseData`
+    /// and whether the close is `fatal`.
+    Closed,
+    /// The connection can't go to the requested state from the one it is in.
+    State,
+    /// The authorization doesn't grant a scope the command needs,
+    /// the payload has the `scope` and the `command`.
+    MissingScope,
+    /// The user isn't in the voice channel we are in.
+    UnknownMember,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,95 +69,1057 @@ pub struct IpcError {
     pub payload: Option<Value>,
 }
 
-pub struct SendIPCClient {
-    pub ipc_client: DiscordIpcClient,
-    pub api_client: DiscordAPIClient,
+impl IpcError {
+    pub fn unexpected_response(response: Response) -> Self {
+        IpcError {
+            error_type: IpcErrorType::EventReceive,
+            message: format!("Unexpected response received.\n{:?}", response),
+            payload: None,
+        }
+    }
+
+    /// Whether the socket is gone or discord closed it. A request that timed out
+    /// is not, discord may just be slow to answer that one.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(
+            self.error_type,
+            IpcErrorType::Connect | IpcErrorType::Closed
+        )
+    }
+
+    /// The code and reason discord gave when it closed the connection.
+    pub fn close_data(&self) -> Option<CloseData> {
+        match (&self.error_type, &self.payload) {
+            (IpcErrorType::Closed, Some(payload)) => serde_json::from_value(payload.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    fn closed_by_discord(payload: Value) -> Self {
+        let close = serde_json::from_value::<CloseData>(payload).unwrap_or(CloseData {
+            code: 0,
+            message: String::new(),
+        });
+        IpcError {
+            error_type: IpcErrorType::Closed,
+            message: format!(
+                "Discord closed the connection.\n{} ({})",
+                close.message, close.code
+            ),
+            // the UI only gives up when reconnecting can't help
+            payload: Some(json!({
+                "code": close.code,
+                "message": close.message,
+                "fatal": close.is_fatal(),
+            })),
+        }
+    }
+
+    fn connection_closed() -> Self {
+        IpcError {
+            error_type: IpcErrorType::Connect,
+            message: "The connection to discord was closed.".to_string(),
+            payload: None,
+        }
+    }
 }
 
-pub struct ReceiveIPCClient {
-    pub ipc_client: DiscordIpcClient,
+/// DISPATCH events and other frames that don't answer one of our requests.
+/// The channel ends with a `Connect` error once the connection is gone.
+pub type Events = mpsc::UnboundedReceiver<Result<Message, IpcError>>;
+
+type Reply = Result<Response, ReplyError>;
+
+enum ReplyError {
+    /// Discord answered with an ERROR.
+    Rejected(ErrorData),
+    /// The reply came but couldn't be decoded.
+    Undecodable(IpcError),
 }
 
-impl SendIPCClient {
-    pub fn new(c: DiscordIpcClient) -> Self {
-        Self {
-            ipc_client: c,
-            api_client: DiscordAPIClient::new(),
+/// How long a command waits for its reply unless the config says otherwise.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `probe` waits for a client to answer. A stale socket never does.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// One RPC connection to discord.
+/// Replies are handed to the request with the same nonce,
+/// everything else is pushed to the event channel.
+pub struct Connection {
+    writer: StdMutex<Writer>,
+    // the reason once the connection is closed
+    pending: StdMutex<Result<HashMap<String, oneshot::Sender<Reply>>, IpcError>>,
+    request_timeout: Duration,
+    // what AUTHENTICATE granted, nothing is checked before that
+    scopes: StdMutex<Option<Vec<String>>>,
+    // held while our voice settings are read and changed, so changes don't interleave
+    pub(super) voice_settings_turn: AsyncMutex<()>,
+    // held while the user is asked for more scopes, so they are asked once
+    pub(super) grant_turn: AsyncMutex<()>,
+}
+
+/// A request waiting for its reply. Dropping it, because it timed out or the
+/// caller gave up on it, forgets the nonce so a late reply is thrown away.
+struct PendingRequest<'a> {
+    connection: &'a Connection,
+    nonce: String,
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        if let Ok(pending) = self.connection.pending.lock().unwrap().as_mut() {
+            pending.remove(&self.nonce);
         }
     }
+}
 
-    pub async fn send(&mut self, payload: Value) -> Result<Value, IpcError> {
-        let nonce = payload["nonce"].clone();
-        println!("Sent payload: {}", payload.clone());
-        if let Err(err) = self.ipc_client.send(payload.clone(), 1) {
-            // error while sending data to discord ipc
-            return Err(IpcError {
-                error_type: IpcErrorType::EventSend,
-                message: format!("Failed to decode response.\n{}", err),
+pub struct IpcClient {
+    connection: Option<Arc<Connection>>,
+    session: Option<JoinHandle<()>>,
+    // the socket of the discord client we stick to, any client when `None`
+    instance: Option<PathBuf>,
+    request_timeout: Duration,
+    vc_state: SharedVoiceChannelState,
+    state: SharedConnectionState,
+    // whose tokens are used
+    profile: String,
+    // asked for in AUTHORIZE
+    scopes: Vec<String>,
+    voice_thresholds: VoiceConnectionThresholds,
+    // shared so token requests don't have to hold the client lock
+    pub api_client: Arc<DiscordAPIClient>,
+}
+
+/// Where and how to open a connection, see `IpcClient::opener`.
+pub struct Opener {
+    instance: Option<PathBuf>,
+    request_timeout: Duration,
+}
+
+fn encode(command: &Command, nonce: &str) -> Result<Value, IpcError> {
+    match command.to_payload(nonce) {
+        Ok(p) => Ok(p),
+        Err(err) => Err(IpcError {
+            error_type: IpcErrorType::EventEncode,
+            message: format!(
+                "Failed to encode {} command.\n{}",
+                command.command_type(),
+                err
+            ),
+            payload: None,
+        }),
+    }
+}
+
+fn connect_error(err: io::Error) -> IpcError {
+    IpcError {
+        error_type: IpcErrorType::Connect,
+        message: format!("Failed to connect to discord.\n{}", err),
+        payload: None,
+    }
+}
+
+fn reader_error(err: io::Error) -> IpcError {
+    IpcError {
+        error_type: IpcErrorType::CreateClient,
+        message: format!("Failed to start the socket reader.\n{}", err),
+        payload: None,
+    }
+}
+
+impl Connection {
+    /// Sends the handshake and waits for READY up to `timeout`.
+    fn handshake(
+        socket: &mut Socket,
+        client_id: &str,
+        timeout: Duration,
+    ) -> Result<ReadyData, IpcError> {
+        let handshake = json!({ "v": 1, "client_id": client_id });
+        socket::write_frame(socket, OP_HANDSHAKE, &handshake).map_err(connect_error)?;
+        match socket::read_frame_within(socket, timeout) {
+            Ok((OP_FRAME, payload)) if payload["evt"] == "READY" => {
+                match serde_json::from_value(payload["data"].clone()) {
+                    Ok(ready) => Ok(ready),
+                    Err(err) => Err(IpcError {
+                        error_type: IpcErrorType::EventDecode,
+                        message: format!("Failed to decode READY.\n{}", err),
+                        payload: Some(payload),
+                    }),
+                }
+            }
+            // e.g. an invalid client id
+            Ok((OP_CLOSE, payload)) => Err(IpcError::closed_by_discord(payload)),
+            Ok((_, payload)) => Err(IpcError {
+                error_type: IpcErrorType::Connect,
+                message: "Discord refused the connection.".to_string(),
                 payload: Some(payload),
-            });
+            }),
+            // e.g. a discord client that is still starting up
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => Err(IpcError {
+                error_type: IpcErrorType::Timeout,
+                message: format!(
+                    "Discord did not answer the handshake within {}ms.",
+                    timeout.as_millis()
+                ),
+                payload: None,
+            }),
+            Err(err) => Err(connect_error(err)),
         }
-        let response = loop {
-            let (_opcode, response) = match self.ipc_client.recv() {
-                Ok(r) => r,
+    }
+
+    /// Connects to the socket at `path` just to see who answers.
+    pub fn probe(path: &Path, client_id: &str) -> Result<ReadyData, IpcError> {
+        let mut socket = socket::connect(path).map_err(connect_error)?;
+        let ready = Self::handshake(&mut socket, client_id, PROBE_TIMEOUT);
+        let _ = socket::write_frame(&mut socket, OP_CLOSE, &json!({}));
+        ready
+    }
+
+    /// Connects to the socket at `path` and does the handshake.
+    fn connect_to(path: &Path, client_id: &str, timeout: Duration) -> Result<Socket, IpcError> {
+        let mut socket = socket::connect(path).map_err(connect_error)?;
+        Self::handshake(&mut socket, client_id, timeout)?;
+        Ok(socket)
+    }
+
+    /// Tries the sockets in turn until a client does the handshake. A socket
+    /// left behind by a crashed client may still accept us, but never answers.
+    fn connect_to_any(client_id: &str, timeout: Duration) -> Result<Socket, IpcError> {
+        let mut handshake_error = None;
+        for (path, _) in discovery::candidates() {
+            match Self::connect_to(&path, client_id, timeout) {
+                Ok(socket) => return Ok(socket),
+                Err(err) if matches!(err.error_type, IpcErrorType::Connect) => {}
+                // more telling than "not running" if no other client answers either
+                Err(err) => handshake_error = Some(err),
+            }
+        }
+        Err(handshake_error.unwrap_or_else(|| IpcError {
+            error_type: IpcErrorType::Connect,
+            message: "Failed to connect to discord.\nDiscord is not running.".to_string(),
+            payload: None,
+        }))
+    }
+
+    /// Connects to `instance`, or the first client that answers,
+    /// does the handshake and starts reading frames.
+    fn open(
+        instance: Option<&Path>,
+        client_id: &str,
+        request_timeout: Duration,
+    ) -> Result<(Arc<Self>, Events), IpcError> {
+        let socket = match instance {
+            Some(path) => Self::connect_to(path, client_id, request_timeout)?,
+            None => Self::connect_to_any(client_id, request_timeout)?,
+        };
+
+        let (reader, writer) = socket::split(socket).map_err(reader_error)?;
+        let connection = Arc::new(Self {
+            writer: StdMutex::new(writer),
+            pending: StdMutex::new(Ok(HashMap::new())),
+            request_timeout,
+            scopes: StdMutex::new(None),
+            voice_settings_turn: AsyncMutex::new(()),
+            grant_turn: AsyncMutex::new(()),
+        });
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let reader_connection = Arc::clone(&connection);
+        // reads block until discord sends something,
+        // so they get their own thread instead of an async runtime worker
+        thread::Builder::new()
+            .name("discord-ipc-reader".to_string())
+            .spawn(move || reader_connection.read_loop(reader, events_tx))
+            .map_err(reader_error)?;
+        Ok((connection, events_rx))
+    }
+
+    fn read_loop(
+        &self,
+        mut reader: Reader,
+        events: mpsc::UnboundedSender<Result<Message, IpcError>>,
+    ) {
+        let reason = loop {
+            let (opcode, payload) = match socket::read_frame(&mut reader) {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    let _ = events.send(Err(IpcError {
+                        error_type: IpcErrorType::EventDecode,
+                        message: format!("Failed to decode the frame.\n{}", err),
+                        payload: None,
+                    }));
+                    continue;
+                }
                 Err(err) => {
-                    return Err(IpcError {
+                    break IpcError {
+                        error_type: IpcErrorType::Connect,
+                        message: format!("Lost the connection to discord.\n{}", err),
+                        payload: None,
+                    };
+                }
+            };
+            match opcode {
+                OP_FRAME => {}
+                OP_PING => {
+                    let mut writer = self.writer.lock().unwrap();
+                    if let Err(err) = socket::write_frame(&mut *writer, OP_PONG, &payload) {
+                        log_error(
+                            "ipc".to_string(),
+                            format!("Failed to answer ping.\n{}", err),
+                        );
+                    }
+                    continue;
+                }
+                OP_CLOSE => break IpcError::closed_by_discord(payload),
+                // pongs and anything newer
+                _ => continue,
+            }
+            match Message::from_payload(payload.clone()) {
+                Ok(message) => {
+                    if let Some(message) = self.route(message) {
+                        let _ = events.send(Ok(message));
+                    }
+                }
+                Err(err) => {
+                    let nonce = payload["nonce"].as_str().map(str::to_string);
+                    let error = IpcError {
                         error_type: IpcErrorType::EventDecode,
-                        message: format!("Failed to decode response.\n{}", err),
+                        message: format!("Failed to decode the event.\n{}", err),
                         payload: Some(payload),
-                    });
+                    };
+                    // a reply the request waiting for it should hear about
+                    let error = match nonce {
+                        Some(nonce) => self.answer(&nonce, Err(ReplyError::Undecodable(error))),
+                        None => Some(Err(ReplyError::Undecodable(error))),
+                    };
+                    if let Some(Err(ReplyError::Undecodable(error))) = error {
+                        let _ = events.send(Err(error));
+                    }
                 }
-            };
-            if !response["nonce"].is_null() && response["nonce"] == nonce {
-                break response;
             }
-            return Err(IpcError {
-                error_type: IpcErrorType::EventReceive,
-                message: format!("Invalid message received.\n{}", response),
-                payload: Some(payload),
-            });
         };
-        Ok(response)
+        // wakes up every request that is still waiting for its reply
+        *self.pending.lock().unwrap() = Err(reason.clone());
+        let _ = events.send(Err(reason));
+    }
+
+    /// Hands replies to the request waiting for them and returns everything else.
+    /// Replies nobody waits for anymore are dropped.
+    fn route(&self, message: Message) -> Option<Message> {
+        let (nonce, reply) = match message {
+            Message::Response {
+                nonce: Some(nonce),
+                response,
+            } => (nonce, Ok(response)),
+            Message::Error {
+                nonce: Some(nonce),
+                error,
+                ..
+            } => (nonce, Err(ReplyError::Rejected(error))),
+            message => return Some(message),
+        };
+        let _ = self.answer(&nonce, reply);
+        None
     }
-}
 
-impl ReceiveIPCClient {
-    pub fn new(c: DiscordIpcClient) -> Self {
-        Self { ipc_client: c }
+    /// Hands the reply to the request waiting for it,
+    /// returning it when nobody waits for it anymore.
+    fn answer(&self, nonce: &str, reply: Reply) -> Option<Reply> {
+        let sender = self
+            .pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok()
+            .and_then(|pending| pending.remove(nonce));
+        match sender {
+            Some(sender) => sender.send(reply).err(),
+            None => Some(reply),
+        }
     }
 
-    pub async fn send(&mut self, payload: Value) -> Result<(), IpcError> {
-        if let Err(err) = self.ipc_client.send(payload.clone(), 1) {
+    /// Writes the command and returns its nonce and where its reply will arrive.
+    fn request(&self, command: &Command) -> Result<(String, oneshot::Receiver<Reply>), IpcError> {
+        let nonce = Uuid::new_v4().to_string();
+        let payload = encode(command, &nonce)?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Ok(pending) => pending.insert(nonce.clone(), reply_tx),
+            Err(reason) => return Err(reason.clone()),
+        };
+
+        let written = socket::write_frame(&mut *self.writer.lock().unwrap(), OP_FRAME, &payload);
+        if let Err(err) = written {
             // error while sending data to discord ipc
+            if let Ok(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&nonce);
+            }
             return Err(IpcError {
                 error_type: IpcErrorType::EventSend,
-                message: format!("Failed to decode response.\n{}", err),
+                message: format!("Failed to send command.\n{}", err),
                 payload: Some(payload),
             });
         }
-        Ok(())
+        Ok((nonce, reply_rx))
+    }
+
+    /// Remembers the scopes AUTHENTICATE granted, commands needing others fail from now on.
+    pub fn set_scopes(&self, scopes: Vec<String>) {
+        *self.scopes.lock().unwrap() = Some(scopes);
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match self.scopes.lock().unwrap().as_ref() {
+            Some(granted) => granted.iter().any(|s| s == scope),
+            None => true,
+        }
+    }
+
+    /// Fails up front instead of waiting for discord's ERROR.
+    fn check_scope(&self, command: &Command) -> Result<(), IpcError> {
+        match scope::required_by(command) {
+            Some(scope) if !self.has_scope(scope) => Err(IpcError {
+                error_type: IpcErrorType::MissingScope,
+                message: format!(
+                    "Missing scope {scope} for {}. Add it to `scopes` in the config and log in again.",
+                    command.command_type()
+                ),
+                payload: Some(json!({
+                    "scope": scope,
+                    "command": command.command_type().to_string()
+                })),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the command and waits for its reply up to the request timeout.
+    pub async fn send(&self, command: Command) -> Result<Response, IpcError> {
+        self.send_timeout(command, Some(self.request_timeout)).await
+    }
+
+    /// Sends the command and waits for its reply up to `timeout`, or forever with `None`.
+    pub async fn send_timeout(
+        &self,
+        command: Command,
+        timeout: Option<Duration>,
+    ) -> Result<Response, IpcError> {
+        self.check_scope(&command)?;
+        let (nonce, reply) = self.request(&command)?;
+        let _pending = PendingRequest {
+            connection: self,
+            nonce,
+        };
+        let reply = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, reply).await {
+                Ok(r) => r,
+                Err(_) => {
+                    return Err(IpcError {
+                        error_type: IpcErrorType::Timeout,
+                        message: format!(
+                            "Discord did not answer the {} command within {}ms.",
+                            command.command_type(),
+                            timeout.as_millis()
+                        ),
+                        payload: None,
+                    });
+                }
+            },
+            None => reply.await,
+        };
+        match reply {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(ReplyError::Undecodable(error))) => Err(error),
+            Ok(Err(ReplyError::Rejected(error))) => Err(IpcError {
+                error_type: IpcErrorType::EventSend,
+                message: format!(
+                    "Discord rejected the {} command.\n{} ({})",
+                    command.command_type(),
+                    error.message,
+                    error.code
+                ),
+                payload: None,
+            }),
+            Err(_) => Err(self.close_reason()),
+        }
     }
 
     pub async fn subscribe(
-        &mut self,
-        event_name: &str,
-        args: Value,
+        &self,
+        event: EventType,
+        args: SubscribeArgs,
         is_subscribe: bool,
     ) -> Result<(), IpcError> {
-        let event_type = if is_subscribe {
-            json!("SUBSCRIBE")
+        let (command, error_type) = if is_subscribe {
+            (Command::Subscribe(event, args), IpcErrorType::Subscribe)
         } else {
-            json!("UNSUBSCRIBE")
+            (Command::Unsubscribe(event, args), IpcErrorType::Unsubscribe)
         };
-        let payload = serde_json::json!({
-            "nonce": Uuid::new_v4().to_string(),
-            "cmd": event_type,
-            "evt": event_name,
-            "args": args
+        match self.send(command).await {
+            Ok(_) => Ok(()),
+            // keep connection errors as they are, the session reconnects on them
+            Err(err) if err.is_connection_lost() => Err(err),
+            // the payload tells which scope to grant
+            Err(err) if matches!(err.error_type, IpcErrorType::MissingScope) => Err(IpcError {
+                message: format!("Failed to subscribe to {}.\n{}", event, err.message),
+                ..err
+            }),
+            Err(err) => Err(IpcError {
+                error_type,
+                message: format!("Failed to subscribe to {}.\n{}", event, err.message),
+                payload: None,
+            }),
+        }
+    }
+
+    /// Why the connection closed, for requests that lost their reply with it.
+    fn close_reason(&self) -> IpcError {
+        match self.pending.lock().unwrap().as_ref() {
+            Err(reason) => reason.clone(),
+            Ok(_) => IpcError::connection_closed(),
+        }
+    }
+
+    /// Says goodbye to discord and stops the reader.
+    pub fn close(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        let _ = socket::write_frame(&mut *writer, OP_CLOSE, &json!({}));
+        socket::shutdown(&writer);
+    }
+}
+
+impl Opener {
+    /// The socket is opened on a blocking thread, the handshake times out like a request.
+    pub async fn open(self) -> Result<(Arc<Connection>, Events), IpcError> {
+        let opened = spawn_blocking(move || {
+            Connection::open(
+                self.instance.as_deref(),
+                dotenv!("CLIENT_ID"),
+                self.request_timeout,
+            )
+        })
+        .await;
+        match opened {
+            Ok(opened) => opened,
+            Err(err) => Err(IpcError {
+                error_type: IpcErrorType::CreateClient,
+                message: format!("Failed to open the connection.\n{}", err),
+                payload: None,
+            }),
+        }
+    }
+}
+
+impl IpcClient {
+    pub fn new() -> Self {
+        Self {
+            connection: None,
+            session: None,
+            instance: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            vc_state: SharedVoiceChannelState::default(),
+            state: SharedConnectionState::default(),
+            profile: DEFAULT_PROFILE.to_string(),
+            scopes: scope::default_scopes(),
+            voice_thresholds: VoiceConnectionThresholds::default(),
+            api_client: Arc::new(DiscordAPIClient::new()),
+        }
+    }
+
+    /// How long commands wait for their reply on the connections opened from now on.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn with_api_client(mut self, api_client: DiscordAPIClient) -> Self {
+        self.api_client = Arc::new(api_client);
+        self
+    }
+
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// The scopes authorizations ask for.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    pub fn with_voice_thresholds(mut self, voice_thresholds: VoiceConnectionThresholds) -> Self {
+        self.voice_thresholds = voice_thresholds;
+        self
+    }
+
+    /// When the voice connection is bad enough to warn about.
+    pub fn voice_thresholds(&self) -> &VoiceConnectionThresholds {
+        &self.voice_thresholds
+    }
+
+    pub fn with_profile(mut self, profile: String) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// The profile whose tokens the next connection authenticates with.
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// Takes effect on the next connection, close the current one first.
+    pub fn set_profile(&mut self, profile: String) {
+        self.profile = profile;
+    }
+
+    /// Sticks to the discord client listening at `instance`, or any client with `None`.
+    pub fn pin_instance(&mut self, instance: Option<PathBuf>) {
+        self.instance = instance;
+    }
+
+    /// Opens a new connection to discord, replacing the current one. The app
+    /// opens them with `opener` instead, so the client isn't locked meanwhile.
+    #[cfg(test)]
+    pub async fn connect(&mut self) -> Result<(Arc<Connection>, Events), IpcError> {
+        if let Some(connection) = self.connection.take() {
+            connection.close();
+        }
+        let (connection, events) = self.opener().open().await?;
+        self.set_connection(Arc::clone(&connection));
+        Ok((connection, events))
+    }
+
+    /// Opens connections like `connect`, without needing the client locked meanwhile.
+    pub fn opener(&self) -> Opener {
+        Opener {
+            instance: self.instance.clone(),
+            request_timeout: self.request_timeout,
+        }
+    }
+
+    /// Makes the opened connection the current one, closing the one before.
+    pub fn set_connection(&mut self, connection: Arc<Connection>) {
+        if let Some(old) = self.connection.replace(connection) {
+            old.close();
+        }
+    }
+
+    /// The current connection. Requests go through it without keeping the client locked,
+    /// so a command never waits for the reply of another one.
+    pub fn connection(&self) -> Result<Arc<Connection>, IpcError> {
+        match &self.connection {
+            Some(c) => Ok(Arc::clone(c)),
+            None => Err(IpcError {
+                error_type: IpcErrorType::Connect,
+                message: "Not connected to discord.".to_string(),
+                payload: None,
+            }),
+        }
+    }
+
+    /// The voice channel state the session keeps up to date.
+    pub fn vc_state(&self) -> SharedVoiceChannelState {
+        Arc::clone(&self.vc_state)
+    }
+
+    /// Where the connection is in its lifecycle. Whoever opens or closes
+    /// the connection moves it along, so the change can be reported.
+    pub fn connection_state(&self) -> SharedConnectionState {
+        self.state.clone()
+    }
+
+    /// Keeps the task handling the connection's events, so closing the client stops it too.
+    pub fn set_session(&mut self, session: JoinHandle<()>) {
+        if let Some(old) = self.session.replace(session) {
+            old.abort();
+        }
+    }
+
+    /// Stops the session and closes the connection without reconnecting.
+    pub fn close(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.abort();
+        }
+        if let Some(connection) = self.connection.take() {
+            connection.close();
+        }
+        *self.vc_state.lock().unwrap() = VoiceChannelState::default();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::ipc::{
+        mock::{MockDiscord, Script},
+        protocol::{
+            Event, GetChannelsArgs, SelectVoiceChannelArgs, SetActivityArgs, SetVoiceSettingsArgs,
+            VoiceSettings,
+        },
+    };
+    use std::time::{Duration, Instant};
+    use tauri::async_runtime::{block_on, spawn, Mutex};
+
+    #[test]
+    fn replies_are_routed_past_dispatches() {
+        let _discord = MockDiscord::start(
+            Script::new()
+                .dispatch(
+                    "GET_VOICE_SETTINGS",
+                    "VOICE_SETTINGS_UPDATE",
+                    json!({ "mute": false, "deaf": true }),
+                )
+                .reply("GET_VOICE_SETTINGS", json!({ "mute": true, "deaf": false })),
+        );
+        let mut client = IpcClient::new();
+        let (connection, mut events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let response = block_on(connection.send(Command::GetVoiceSettings))
+            .map_err(|err| err.message)
+            .unwrap();
+        let Response::GetVoiceSettings(settings) = response else {
+            panic!("Unexpected response {:?}", response);
+        };
+        assert!(settings.mute);
+
+        let event = block_on(events.recv()).unwrap().map_err(|err| err.message);
+        assert!(matches!(
+            event,
+            Ok(Message::Event(Event::VoiceSettingsUpdate(settings))) if settings.deaf
+        ));
+        client.close();
+    }
+
+    #[test]
+    fn undecodable_replies_answer_their_request() {
+        let _discord =
+            MockDiscord::start(Script::new().reply("GET_VOICE_SETTINGS", json!({ "mute": true })));
+        let mut client = IpcClient::new();
+        let (connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let result = block_on(connection.send_timeout(Command::GetVoiceSettings, None));
+        let Err(err) = result else {
+            panic!("a reply without `deaf` was decoded");
+        };
+        assert!(matches!(err.error_type, IpcErrorType::EventDecode));
+        client.close();
+    }
+
+    #[test]
+    fn commands_without_their_scope_fail_up_front() {
+        let discord = MockDiscord::start(Script::new().reply(
+            "AUTHENTICATE",
+            json!({
+                "user": { "id": "100", "username": "me", "avatar": null },
+                "scopes": ["rpc", "identify", "rpc.voice.read"]
+            }),
+        ));
+        let mut client = IpcClient::new();
+        let (connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+        block_on(connection.authenticate("token".to_string()))
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let command = Command::SetVoiceSettings(SetVoiceSettingsArgs {
+            mute: Some(true),
+            ..Default::default()
+        });
+        let Err(err) = block_on(connection.send(command)) else {
+            panic!("the command was sent without rpc.voice.write");
+        };
+        assert!(matches!(err.error_type, IpcErrorType::MissingScope));
+        assert_eq!(err.payload.unwrap()["scope"], "rpc.voice.write");
+        assert!(discord
+            .received()
+            .iter()
+            .all(|payload| payload["cmd"] != "SET_VOICE_SETTINGS"));
+        client.close();
+    }
+
+    /// Sends the command on a connection that was only granted `rpc` and `identify`.
+    fn assert_needs_scope(command: Command, scope: &str) {
+        let discord = MockDiscord::start(Script::new().reply(
+            "AUTHENTICATE",
+            json!({
+                "user": { "id": "100", "username": "me", "avatar": null },
+                "scopes": ["rpc", "identify"]
+            }),
+        ));
+        let mut client = IpcClient::new();
+        let (connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+        block_on(connection.authenticate("token".to_string()))
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let cmd = command.command_type().to_string();
+        let Err(err) = block_on(connection.send(command)) else {
+            panic!("{cmd} was sent without {scope}");
+        };
+        assert!(matches!(err.error_type, IpcErrorType::MissingScope));
+        assert_eq!(err.payload.unwrap()["scope"], scope);
+        assert!(discord
+            .received()
+            .iter()
+            .all(|payload| payload["cmd"] != cmd.as_str()));
+        client.close();
+    }
+
+    #[test]
+    fn guild_list_needs_the_guilds_scope() {
+        assert_needs_scope(Command::GetGuilds, "guilds");
+    }
+
+    #[test]
+    fn channel_list_needs_the_guilds_scope() {
+        let command = Command::GetChannels(GetChannelsArgs {
+            guild_id: "400".to_string(),
+        });
+        assert_needs_scope(command, "guilds");
+    }
+
+    #[test]
+    fn joining_a_voice_channel_needs_voice_write() {
+        let command = Command::SelectVoiceChannel(SelectVoiceChannelArgs {
+            channel_id: Some("300".to_string()),
+            force: None,
+        });
+        assert_needs_scope(command, "rpc.voice.write");
+    }
+
+    #[test]
+    fn setting_the_activity_needs_activities_write() {
+        let command = Command::SetActivity(SetActivityArgs {
+            pid: 1,
+            activity: None,
         });
-        if let Err(err) = self.send(payload.clone()).await {
-            return Err(err);
+        assert_needs_scope(command, "rpc.activities.write");
+    }
+
+    #[test]
+    fn toggles_start_from_the_cached_voice_settings() {
+        let discord = MockDiscord::start(
+            Script::new()
+                .reply(
+                    "GET_VOICE_SETTINGS",
+                    json!({ "mute": false, "deaf": false }),
+                )
+                .reply("SET_VOICE_SETTINGS", json!({ "mute": true, "deaf": false })),
+        );
+        let mut client = IpcClient::new();
+        let (connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+        let vc_state = client.vc_state();
+        let toggle_mute = |settings: &VoiceSettings| SetVoiceSettingsArgs {
+            mute: Some(!settings.mute),
+            ..Default::default()
+        };
+
+        // nothing cached yet, so the settings are fetched first
+        let settings = block_on(connection.update_voice_settings(&vc_state, toggle_mute))
+            .map_err(|err| err.message)
+            .unwrap();
+        assert!(settings.mute && vc_state.lock().unwrap().mute);
+        // the reply of the first toggle is what the second one flips
+        block_on(connection.update_voice_settings(&vc_state, toggle_mute))
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let received = discord.received();
+        let sent = |cmd: &str| -> Vec<Value> {
+            received
+                .iter()
+                .filter(|payload| payload["cmd"] == cmd)
+                .map(|payload| payload["args"].clone())
+                .collect()
+        };
+        assert_eq!(sent("GET_VOICE_SETTINGS").len(), 1);
+        assert_eq!(
+            sent("SET_VOICE_SETTINGS"),
+            [json!({ "mute": true }), json!({ "mute": false })]
+        );
+        client.close();
+    }
+
+    #[test]
+    fn requests_fail_once_the_connection_is_closed() {
+        let discord = MockDiscord::start(Script::new());
+        let mut client = IpcClient::new();
+        let (connection, mut events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+
+        drop(discord);
+        let closed = block_on(events.recv()).unwrap();
+        assert!(matches!(
+            closed,
+            Err(IpcError {
+                error_type: IpcErrorType::Connect,
+                ..
+            })
+        ));
+        let result = block_on(connection.send(Command::GetVoiceSettings));
+        assert!(matches!(
+            result,
+            Err(IpcError {
+                error_type: IpcErrorType::Connect,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn requests_do_not_wait_for_each_other() {
+        // lots of events in front of the reply, like a busy voice channel
+        let mut script = Script::new().ignore("GET_SELECTED_VOICE_CHANNEL");
+        for _ in 0..500 {
+            script = script.dispatch(
+                "GET_VOICE_SETTINGS",
+                "SPEAKING_START",
+                json!({ "user_id": "200", "channel_id": "300" }),
+            );
+        }
+        let discord = MockDiscord::start(script.reply(
+            "GET_VOICE_SETTINGS",
+            json!({ "mute": false, "deaf": false }),
+        ));
+        let client = Arc::new(Mutex::new(IpcClient::new()));
+        let (connection, _events) = block_on(async { client.lock().await.connect().await })
+            .map_err(|err| err.message)
+            .unwrap();
+
+        // a request discord never answers
+        let stuck = spawn(async move { connection.send(Command::GetSelectedVoiceChannel).await });
+        discord.wait_for_command("GET_SELECTED_VOICE_CHANNEL");
+
+        let started = Instant::now();
+        let connection = block_on(client.lock())
+            .connection()
+            .map_err(|err| err.message)
+            .unwrap();
+        block_on(connection.send(Command::GetVoiceSettings))
+            .map_err(|err| err.message)
+            .unwrap();
+        let elapsed = started.elapsed();
+        // well under the request timeout, it didn't wait for the stuck request
+        assert!(
+            elapsed < Duration::from_secs(1),
+            "GET_VOICE_SETTINGS took {elapsed:?} behind 500 events"
+        );
+
+        stuck.abort();
+        block_on(client.lock()).close();
+    }
+
+    #[test]
+    fn timed_out_request_leaves_no_stale_reply() {
+        let _discord = MockDiscord::start(
+            Script::new()
+                .delay("GET_VOICE_SETTINGS", Duration::from_millis(300))
+                .reply("GET_VOICE_SETTINGS", json!({ "mute": true, "deaf": false }))
+                .reply("GET_SELECTED_VOICE_CHANNEL", Value::Null),
+        );
+        let mut client = IpcClient::new().with_request_timeout(Duration::from_millis(100));
+        let (connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let timed_out = block_on(connection.send(Command::GetVoiceSettings));
+        assert!(matches!(
+            timed_out,
+            Err(IpcError {
+                error_type: IpcErrorType::Timeout,
+                ..
+            })
+        ));
+
+        // the late voice settings arrive first, but belong to nobody
+        let response = block_on(connection.send_timeout(Command::GetSelectedVoiceChannel, None))
+            .map_err(|err| err.message)
+            .unwrap();
+        assert!(matches!(response, Response::GetSelectedVoiceChannel(None)));
+        assert!(connection
+            .pending
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_ok_and(|pending| pending.is_empty()));
+        client.close();
+    }
+
+    #[test]
+    fn pings_are_answered() {
+        let discord = MockDiscord::start(Script::new());
+        let mut client = IpcClient::new();
+        let (_connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+
+        discord.ping(json!({ "nonce": "ping-1" }));
+        assert_eq!(discord.wait_for_pong(), json!({ "nonce": "ping-1" }));
+        client.close();
+    }
+
+    #[test]
+    fn close_frames_carry_discords_reason() {
+        let discord = MockDiscord::start(Script::new());
+        let mut client = IpcClient::new();
+        let (connection, mut events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+
+        discord.close_connections(4003, "Token revoked");
+        let closed = match block_on(events.recv()).unwrap() {
+            Err(err) => err,
+            Ok(message) => panic!("Unexpected message {:?}", message),
+        };
+        let close = closed.close_data().unwrap();
+        assert_eq!(
+            (close.code, close.message.as_str()),
+            (4003, "Token revoked")
+        );
+        assert!(!close.is_fatal());
+        assert_eq!(closed.payload.unwrap()["fatal"], false);
+
+        // requests after the close get the same reason
+        let result = block_on(connection.send(Command::GetVoiceSettings));
+        assert!(matches!(
+            result.map_err(|err| err.close_data().map(|close| close.code)),
+            Err(Some(4003))
+        ));
+    }
+
+    #[test]
+    fn unanswered_handshake_times_out() {
+        let _discord = MockDiscord::start(Script::new().ignore_handshake());
+        let mut client = IpcClient::new().with_request_timeout(Duration::from_millis(200));
+
+        let Err(err) = block_on(client.connect()) else {
+            panic!("The handshake was never answered");
+        };
+        assert!(matches!(err.error_type, IpcErrorType::Timeout));
+    }
+
+    #[test]
+    fn rejected_handshake_carries_discords_reason() {
+        let _discord =
+            MockDiscord::start(Script::new().reject_handshake(4000, "Invalid Client ID"));
+        let mut client = IpcClient::new();
+
+        let Err(err) = block_on(client.connect()) else {
+            panic!("The handshake should be rejected");
         };
-        Ok(())
+        let close = err.close_data().unwrap();
+        assert_eq!(close.code, 4000);
+        assert!(close.is_fatal());
+        assert_eq!(err.payload.unwrap()["fatal"], true);
     }
 }
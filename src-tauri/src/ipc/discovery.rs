@@ -0,0 +1,204 @@
+//! Finding the discord clients running on this machine.
+//!
+//! Every client listens on the first free `discord-ipc-N` socket (N from 0 to 9).
+//! Flatpak and Snap builds put theirs into their own subdirectory of `XDG_RUNTIME_DIR`.
+
+use std::path::PathBuf;
+
+use dotenvy_macro::dotenv;
+use serde::{Deserialize, Serialize};
+
+use super::{client::Connection, protocol::User};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Release {
+    Stable,
+    Ptb,
+    Canary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sandbox {
+    Native,
+    Flatpak,
+    Snap,
+}
+
+/// A running discord client. `path` pins `connect_ipc` to it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscordInstance {
+    pub path: String,
+    pub sandbox: Sandbox,
+    pub release: Release,
+    /// Who is logged in to the client.
+    pub user: Option<User>,
+}
+
+#[cfg(unix)]
+const SANDBOX_DIRS: [(&str, Sandbox); 5] = [
+    ("", Sandbox::Native),
+    ("app/com.discordapp.Discord", Sandbox::Flatpak),
+    ("app/com.discordapp.DiscordCanary", Sandbox::Flatpak),
+    ("snap.discord", Sandbox::Snap),
+    ("snap.discord-canary", Sandbox::Snap),
+];
+
+/// Every socket a discord client may listen on, in the order they are tried.
+#[cfg(unix)]
+pub fn candidates() -> Vec<(PathBuf, Sandbox)> {
+    use std::env;
+
+    let mut base_dirs: Vec<PathBuf> = Vec::new();
+    for dir in ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+        .iter()
+        .filter_map(|key| env::var(key).ok())
+        .chain(["/tmp".to_string()])
+    {
+        let dir = PathBuf::from(dir);
+        if !base_dirs.contains(&dir) {
+            base_dirs.push(dir);
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for base_dir in base_dirs.iter() {
+        for (sandbox_dir, sandbox) in SANDBOX_DIRS {
+            for i in 0..10 {
+                let path = base_dir.join(sandbox_dir).join(format!("discord-ipc-{i}"));
+                if path.exists() {
+                    candidates.push((path, sandbox));
+                }
+            }
+        }
+    }
+    candidates
+}
+
+/// Every socket a discord client may listen on, in the order they are tried.
+#[cfg(windows)]
+pub fn candidates() -> Vec<(PathBuf, Sandbox)> {
+    (0..10)
+        .map(|i| {
+            (
+                PathBuf::from(format!(r"\\?\pipe\discord-ipc-{i}")),
+                Sandbox::Native,
+            )
+        })
+        .collect()
+}
+
+/// READY doesn't name the release channel, but each one has its own API endpoint.
+fn release_of(api_endpoint: &str) -> Release {
+    if api_endpoint.contains("canary.") {
+        Release::Canary
+    } else if api_endpoint.contains("ptb.") {
+        Release::Ptb
+    } else {
+        Release::Stable
+    }
+}
+
+/// Says hello to every socket and lists the clients that answer.
+pub fn list_discord_instances() -> Vec<DiscordInstance> {
+    candidates()
+        .into_iter()
+        .filter_map(|(path, sandbox)| {
+            let ready = Connection::probe(&path, dotenv!("CLIENT_ID")).ok()?;
+            Some(DiscordInstance {
+                path: path.to_string_lossy().to_string(),
+                sandbox,
+                release: release_of(&ready.config.api_endpoint),
+                user: ready.user,
+            })
+        })
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::ipc::{
+        client::IpcClient,
+        mock::{MockDiscord, Script},
+        protocol::{Command, Response},
+    };
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+    use tauri::async_runtime::block_on;
+
+    #[test]
+    fn lists_every_instance_and_connects_to_the_pinned_one() {
+        let discord = MockDiscord::start_instances(vec![
+            ("discord-ipc-0", Script::new()),
+            (
+                "app/com.discordapp.Discord/discord-ipc-0",
+                Script::new().api_endpoint("//canary.discord.com/api"),
+            ),
+            (
+                "snap.discord/discord-ipc-1",
+                Script::new()
+                    .api_endpoint("//ptb.discord.com/api")
+                    .reply("GET_VOICE_SETTINGS", json!({ "mute": true, "deaf": true })),
+            ),
+        ]);
+
+        let instances: Vec<_> = list_discord_instances()
+            .into_iter()
+            .filter(|instance| instance.path.starts_with(discord.dir().to_str().unwrap()))
+            .collect();
+        let found: Vec<_> = instances
+            .iter()
+            .map(|instance| (instance.sandbox, instance.release))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Sandbox::Native, Release::Stable),
+                (Sandbox::Flatpak, Release::Canary),
+                (Sandbox::Snap, Release::Ptb),
+            ]
+        );
+        assert_eq!(instances[0].user.as_ref().unwrap().username, "mock");
+
+        let mut client = IpcClient::new();
+        client.pin_instance(Some(PathBuf::from(&instances[2].path)));
+        let (connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+        let response = block_on(connection.send(Command::GetVoiceSettings))
+            .map_err(|err| err.message)
+            .unwrap();
+        assert!(matches!(response, Response::GetVoiceSettings(settings) if settings.deaf));
+        client.close();
+    }
+
+    #[test]
+    fn skips_sockets_that_never_answer() {
+        let discord = MockDiscord::start_instances(vec![
+            ("discord-ipc-0", Script::new().ignore_handshake()),
+            (
+                "discord-ipc-1",
+                Script::new().reply("GET_VOICE_SETTINGS", json!({ "mute": true, "deaf": true })),
+            ),
+        ]);
+
+        let started = Instant::now();
+        let instances: Vec<_> = list_discord_instances()
+            .into_iter()
+            .filter(|instance| instance.path.starts_with(discord.dir().to_str().unwrap()))
+            .collect();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(instances.len(), 1);
+        assert!(instances[0].path.ends_with("discord-ipc-1"));
+
+        let mut client = IpcClient::new().with_request_timeout(Duration::from_millis(300));
+        let (connection, _events) = block_on(client.connect())
+            .map_err(|err| err.message)
+            .unwrap();
+        let response = block_on(connection.send(Command::GetVoiceSettings))
+            .map_err(|err| err.message)
+            .unwrap();
+        assert!(matches!(response, Response::GetVoiceSettings(settings) if settings.deaf));
+        client.close();
+    }
+}
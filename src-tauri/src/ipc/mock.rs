@@ -0,0 +1,481 @@
+//! A stand-in for the Discord client's RPC socket, used by the tests.
+//!
+//! The mock listens on `discord-ipc-0` inside a temporary directory, answers the
+//! handshake with READY and replies to every command from a [`Script`].
+//! DISPATCH events can be pushed to the connections that subscribed to them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fs,
+    io::{Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::event::{EventEmitter, EventName};
+
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The variables the mock points at its directory, put back when it stops.
+const ENV_VARS: [&str; 3] = ["XDG_RUNTIME_DIR", "XDG_CONFIG_HOME", "HOME"];
+
+// the client finds the socket through XDG_RUNTIME_DIR,
+// so only one mock can be running in the test process at a time.
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone)]
+enum Reply {
+    Response(Value),
+    Error { code: i64, message: String },
+    Dispatch { evt: String, data: Value },
+}
+
+/// The frames the mock sends back for each command, in order.
+/// Commands without a script get an empty successful response.
+#[derive(Clone, Default)]
+pub struct Script {
+    replies: HashMap<String, Vec<Reply>>,
+    delays: HashMap<String, Duration>,
+    rejection: Option<Value>,
+    silent_handshake: bool,
+    api_endpoint: Option<String>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers `cmd` with a successful response carrying `data`.
+    pub fn reply(mut self, cmd: &str, data: Value) -> Self {
+        self.push(cmd, Reply::Response(data));
+        self
+    }
+
+    /// Answers `cmd` with an ERROR event.
+    pub fn error(mut self, cmd: &str, code: i64, message: &str) -> Self {
+        self.push(
+            cmd,
+            Reply::Error {
+                code,
+                message: message.to_string(),
+            },
+        );
+        self
+    }
+
+    /// Sends a DISPATCH event on the same connection after `cmd` arrives.
+    pub fn dispatch(mut self, cmd: &str, evt: &str, data: Value) -> Self {
+        self.push(
+            cmd,
+            Reply::Dispatch {
+                evt: evt.to_string(),
+                data,
+            },
+        );
+        self
+    }
+
+    /// The API endpoint in READY, which tells the release channel apart.
+    pub fn api_endpoint(mut self, api_endpoint: &str) -> Self {
+        self.api_endpoint = Some(api_endpoint.to_string());
+        self
+    }
+
+    /// Answers the handshake with a CLOSE frame instead of READY.
+    pub fn reject_handshake(mut self, code: i64, message: &str) -> Self {
+        self.rejection = Some(json!({ "code": code, "message": message }));
+        self
+    }
+
+    /// Never answers the handshake, like a discord client stuck starting up.
+    pub fn ignore_handshake(mut self) -> Self {
+        self.silent_handshake = true;
+        self
+    }
+
+    /// Waits before answering `cmd`. The connection reads nothing else meanwhile.
+    pub fn delay(mut self, cmd: &str, delay: Duration) -> Self {
+        self.delays.insert(cmd.to_string(), delay);
+        self
+    }
+
+    /// Never answers `cmd`, like a discord client that hangs.
+    pub fn ignore(mut self, cmd: &str) -> Self {
+        self.replies.entry(cmd.to_string()).or_default();
+        self
+    }
+
+    fn push(&mut self, cmd: &str, reply: Reply) {
+        self.replies.entry(cmd.to_string()).or_default().push(reply);
+    }
+}
+
+struct Connection {
+    stream: UnixStream,
+    subscriptions: HashSet<String>,
+}
+
+#[derive(Default)]
+struct MockState {
+    received: Vec<Value>,
+    pongs: Vec<Value>,
+    connections: Vec<Connection>,
+    closed: bool,
+}
+
+pub struct MockDiscord {
+    dir: PathBuf,
+    sockets: Vec<PathBuf>,
+    state: Arc<Mutex<MockState>>,
+    saved_env: Vec<(&'static str, Option<OsString>)>,
+    // dropped after `drop` has restored the variables
+    _env: MutexGuard<'static, ()>,
+}
+
+impl MockDiscord {
+    /// Starts listening and points `XDG_RUNTIME_DIR` at the mock's socket.
+    /// Tokens saved while the mock runs go to an in-memory keyring,
+    /// the config to the mock's directory.
+    pub fn start(script: Script) -> Self {
+        Self::start_instances(vec![("discord-ipc-0", script)])
+    }
+
+    /// Starts one discord client per socket, given relative to `XDG_RUNTIME_DIR`.
+    pub fn start_instances(instances: Vec<(&str, Script)>) -> Self {
+        let env_guard = ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+        let dir = env::temp_dir().join(format!("discord-vc-status-{}", Uuid::new_v4()));
+        let saved_env = ENV_VARS
+            .iter()
+            .map(|&name| (name, env::var_os(name)))
+            .collect();
+        env::set_var("XDG_RUNTIME_DIR", &dir);
+        // keeps the config the session writes away from the real one
+        env::set_var("XDG_CONFIG_HOME", dir.join("config"));
+        env::set_var("HOME", &dir);
+
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let mut sockets = Vec::new();
+        for (socket, script) in instances {
+            let socket = dir.join(socket);
+            fs::create_dir_all(socket.parent().unwrap())
+                .expect("Failed to create mock socket directory");
+            let listener = UnixListener::bind(&socket).expect("Failed to bind mock socket");
+            sockets.push(socket);
+
+            let accept_state = Arc::clone(&state);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        break;
+                    };
+                    let mut state = accept_state.lock().unwrap();
+                    if state.closed {
+                        break;
+                    }
+                    let id = state.connections.len();
+                    state.connections.push(Connection {
+                        stream: stream.try_clone().expect("Failed to clone mock stream"),
+                        subscriptions: HashSet::new(),
+                    });
+                    drop(state);
+                    let connection_state = Arc::clone(&accept_state);
+                    let script = script.clone();
+                    thread::spawn(move || serve(id, stream, script, connection_state));
+                }
+            });
+        }
+
+        Self {
+            dir,
+            sockets,
+            state,
+            saved_env,
+            _env: env_guard,
+        }
+    }
+
+    /// Where the mock's sockets live.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Every command frame received so far, across all connections.
+    pub fn received(&self) -> Vec<Value> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Waits until a command frame matching `predicate` arrives and returns it.
+    pub fn wait_for(&self, what: &str, predicate: impl Fn(&Value) -> bool) -> Value {
+        poll_until(what, || self.received().into_iter().find(&predicate))
+    }
+
+    pub fn wait_for_command(&self, cmd: &str) -> Value {
+        self.wait_for(cmd, |frame| frame["cmd"] == cmd)
+    }
+
+    pub fn wait_for_subscription(&self, evt: &str) -> Value {
+        self.wait_for(&format!("SUBSCRIBE {evt}"), |frame| {
+            frame["cmd"] == "SUBSCRIBE" && frame["evt"] == evt
+        })
+    }
+
+    /// Sends a PING to every connection.
+    pub fn ping(&self, data: Value) {
+        let mut state = self.state.lock().unwrap();
+        for connection in state.connections.iter_mut() {
+            let _ = write_frame(&mut connection.stream, 3, &data);
+        }
+    }
+
+    pub fn wait_for_pong(&self) -> Value {
+        poll_until("PONG", || self.state.lock().unwrap().pongs.first().cloned())
+    }
+
+    /// Closes every connection with a CLOSE frame, like discord rejecting the client.
+    pub fn close_connections(&self, code: i64, message: &str) {
+        let close = json!({ "code": code, "message": message });
+        let mut state = self.state.lock().unwrap();
+        for connection in state.connections.iter_mut() {
+            let _ = write_frame(&mut connection.stream, 2, &close);
+            let _ = connection.stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    /// Sends a DISPATCH event to every connection subscribed to `evt`.
+    pub fn dispatch(&self, evt: &str, data: Value) {
+        let frame = dispatch_frame(evt, data);
+        let mut state = self.state.lock().unwrap();
+        for connection in state.connections.iter_mut() {
+            if connection.subscriptions.contains(evt) {
+                let _ = write_frame(&mut connection.stream, 1, &frame);
+            }
+        }
+    }
+}
+
+impl Drop for MockDiscord {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.closed = true;
+        for connection in state.connections.iter() {
+            let _ = connection.stream.shutdown(std::net::Shutdown::Both);
+        }
+        drop(state);
+        // wake the accept loops up so they can see that the mock is closed
+        for socket in self.sockets.iter() {
+            let _ = UnixStream::connect(socket);
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+        for (name, value) in self.saved_env.drain(..) {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+    }
+}
+
+fn serve(id: usize, mut stream: UnixStream, script: Script, state: Arc<Mutex<MockState>>) {
+    while let Some((opcode, frame)) = read_frame(&mut stream) {
+        match opcode {
+            // handshake
+            0 => {
+                if let Some(rejection) = &script.rejection {
+                    let _ = write_frame(&mut stream, 2, rejection);
+                    break;
+                }
+                if script.silent_handshake {
+                    continue;
+                }
+                let ready = dispatch_frame(
+                    "READY",
+                    json!({
+                        "v": 1,
+                        "config": {
+                            "cdn_host": "cdn.discordapp.com",
+                            "api_endpoint": script
+                                .api_endpoint
+                                .as_deref()
+                                .unwrap_or("//discord.com/api"),
+                            "environment": "production"
+                        },
+                        "user": {
+                            "id": "0",
+                            "username": "mock",
+                            "discriminator": "0",
+                            "avatar": null
+                        }
+                    }),
+                );
+                if write_frame(&mut stream, 1, &ready).is_err() {
+                    break;
+                }
+            }
+            1 => {
+                let cmd = frame["cmd"].as_str().unwrap_or_default().to_string();
+                {
+                    let mut state = state.lock().unwrap();
+                    let subscriptions = &mut state.connections[id].subscriptions;
+                    if let Some(evt) = frame["evt"].as_str() {
+                        if cmd == "SUBSCRIBE" {
+                            subscriptions.insert(evt.to_string());
+                        } else if cmd == "UNSUBSCRIBE" {
+                            subscriptions.remove(evt);
+                        }
+                    }
+                    state.received.push(frame.clone());
+                }
+                if let Some(delay) = script.delays.get(&cmd) {
+                    thread::sleep(*delay);
+                }
+                for reply in replies_for(&script, &cmd, &frame) {
+                    if write_frame(&mut stream, 1, &reply).is_err() {
+                        return;
+                    }
+                }
+            }
+            // close
+            2 => break,
+            // ping
+            3 if write_frame(&mut stream, 4, &frame).is_err() => break,
+            // pong
+            4 => state.lock().unwrap().pongs.push(frame),
+            _ => {}
+        }
+    }
+}
+
+fn replies_for(script: &Script, cmd: &str, request: &Value) -> Vec<Value> {
+    let nonce = &request["nonce"];
+    let Some(replies) = script.replies.get(cmd) else {
+        let data = if cmd == "SUBSCRIBE" || cmd == "UNSUBSCRIBE" {
+            json!({ "evt": request["evt"] })
+        } else {
+            Value::Null
+        };
+        return vec![json!({
+            "cmd": cmd,
+            "data": data,
+            "evt": null,
+            "nonce": nonce
+        })];
+    };
+    replies
+        .iter()
+        .map(|reply| match reply {
+            Reply::Response(data) => json!({
+                "cmd": cmd,
+                "data": data,
+                "evt": null,
+                "nonce": nonce
+            }),
+            Reply::Error { code, message } => json!({
+                "cmd": cmd,
+                "data": {
+                    "code": code,
+                    "message": message
+                },
+                "evt": "ERROR",
+                "nonce": nonce
+            }),
+            Reply::Dispatch { evt, data } => dispatch_frame(evt, data.clone()),
+        })
+        .collect()
+}
+
+fn dispatch_frame(evt: &str, data: Value) -> Value {
+    json!({
+        "cmd": "DISPATCH",
+        "data": data,
+        "evt": evt,
+        "nonce": null
+    })
+}
+
+fn read_frame(stream: &mut UnixStream) -> Option<(u32, Value)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).ok()?;
+    let opcode = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut body = vec![0u8; length as usize];
+    stream.read_exact(&mut body).ok()?;
+    Some((opcode, serde_json::from_slice(&body).ok()?))
+}
+
+fn write_frame(stream: &mut UnixStream, opcode: u32, payload: &Value) -> std::io::Result<()> {
+    let body = payload.to_string();
+    let mut frame = Vec::with_capacity(8 + body.len());
+    frame.extend_from_slice(&opcode.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(body.as_bytes());
+    stream.write_all(&frame)
+}
+
+fn poll_until<T>(what: &str, mut check: impl FnMut() -> Option<T>) -> T {
+    let started = Instant::now();
+    loop {
+        if let Some(found) = check() {
+            return found;
+        }
+        if started.elapsed() > WAIT_TIMEOUT {
+            panic!("Timed out waiting for {what}");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Records every event the ipc flow emits so tests can assert on them.
+#[derive(Clone, Default)]
+pub struct RecordingEmitter {
+    events: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+impl RecordingEmitter {
+    /// Waits for an event named `event` whose payload matches `predicate`.
+    pub fn wait_until(&self, event: &str, predicate: impl Fn(&Value) -> bool) -> Value {
+        poll_until(event, || {
+            self.events
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(name, payload)| name == event && predicate(payload))
+                .map(|(_, payload)| payload.clone())
+        })
+    }
+
+    pub fn wait_for(&self, event: &str) -> Value {
+        self.wait_until(event, |_| true)
+    }
+
+    /// The payloads of the events named `event` emitted so far.
+    pub fn events(&self, event: &str) -> Vec<Value> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name == event)
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+}
+
+impl EventEmitter for RecordingEmitter {
+    fn emit_event<S: Serialize + Clone>(&self, event_name: EventName, payload: S) {
+        let payload = serde_json::to_value(payload).expect("Failed to encode event payload");
+        self.events
+            .lock()
+            .unwrap()
+            .push((event_name.to_string(), payload));
+    }
+}
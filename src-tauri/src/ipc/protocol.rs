@@ -0,0 +1,826 @@
+//! Typed model of the Discord RPC commands we send and the frames we get back.
+//! See https://discord.com/developers/docs/topics/rpc for the full protocol.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use strum_macros::{Display, EnumString};
+
+#[derive(Display, EnumString, Clone, Copy, Debug, PartialEq, Eq)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum CommandType {
+    Dispatch,
+    Authorize,
+    Authenticate,
+    Subscribe,
+    Unsubscribe,
+    GetSelectedVoiceChannel,
+    GetGuilds,
+    GetChannels,
+    GetVoiceSettings,
+    SetVoiceSettings,
+    SetUserVoiceSettings,
+    SetActivity,
+    SelectVoiceChannel,
+}
+
+#[derive(Display, EnumString, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum EventType {
+    Ready,
+    Error,
+    VoiceSettingsUpdate,
+    VoiceChannelSelect,
+    VoiceStateCreate,
+    VoiceStateUpdate,
+    VoiceStateDelete,
+    SpeakingStart,
+    SpeakingStop,
+    VoiceConnectionStatus,
+}
+
+// outgoing ------------------------------------------------------------------
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AuthorizeArgs {
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    /// Set when the code is exchanged with PKCE.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AuthenticateArgs {
+    pub access_token: String,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SubscribeArgs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GetChannelsArgs {
+    pub guild_id: String,
+}
+
+/// A device and its volume, only the given fields are changed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VoiceIoArgs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// 0 to 100 for the input, 0 to 200 for the output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VoiceModeArgs {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub mode_type: Option<VoiceModeType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_threshold: Option<bool>,
+    /// -100 to 0 dB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shortcut: Option<Vec<ShortcutKeyCombo>>,
+    /// Push to talk release delay in ms, 0 to 2000.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<f64>,
+}
+
+/// Every field is optional, discord only changes the ones that are given.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SetVoiceSettingsArgs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<VoiceIoArgs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<VoiceIoArgs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<VoiceModeArgs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub automatic_gain_control: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo_cancellation: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise_suppression: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qos: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub silence_warning: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deaf: Option<bool>,
+}
+
+/// How loud another user is for us, only the given fields are changed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetUserVoiceSettingsArgs {
+    pub user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pan: Option<Pan>,
+    /// 0 to 200.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f64>,
+    /// Mutes the user for us only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SetActivityArgs {
+    pub pid: u32,
+    /// Leaving this out clears the activity.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity: Option<Value>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SelectVoiceChannelArgs {
+    /// `None` leaves the current voice channel.
+    pub channel_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force: Option<bool>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Authorize(AuthorizeArgs),
+    Authenticate(AuthenticateArgs),
+    Subscribe(EventType, SubscribeArgs),
+    Unsubscribe(EventType, SubscribeArgs),
+    GetSelectedVoiceChannel,
+    GetGuilds,
+    GetChannels(GetChannelsArgs),
+    GetVoiceSettings,
+    SetVoiceSettings(SetVoiceSettingsArgs),
+    SetUserVoiceSettings(SetUserVoiceSettingsArgs),
+    SetActivity(SetActivityArgs),
+    SelectVoiceChannel(SelectVoiceChannelArgs),
+}
+
+impl Command {
+    pub fn command_type(&self) -> CommandType {
+        match self {
+            Command::Authorize(_) => CommandType::Authorize,
+            Command::Authenticate(_) => CommandType::Authenticate,
+            Command::Subscribe(..) => CommandType::Subscribe,
+            Command::Unsubscribe(..) => CommandType::Unsubscribe,
+            Command::GetSelectedVoiceChannel => CommandType::GetSelectedVoiceChannel,
+            Command::GetGuilds => CommandType::GetGuilds,
+            Command::GetChannels(_) => CommandType::GetChannels,
+            Command::GetVoiceSettings => CommandType::GetVoiceSettings,
+            Command::SetVoiceSettings(_) => CommandType::SetVoiceSettings,
+            Command::SetUserVoiceSettings(_) => CommandType::SetUserVoiceSettings,
+            Command::SetActivity(_) => CommandType::SetActivity,
+            Command::SelectVoiceChannel(_) => CommandType::SelectVoiceChannel,
+        }
+    }
+
+    fn args(&self) -> Result<Option<Value>, serde_json::Error> {
+        let args = match self {
+            Command::Authorize(args) => serde_json::to_value(args)?,
+            Command::Authenticate(args) => serde_json::to_value(args)?,
+            Command::Subscribe(_, args) | Command::Unsubscribe(_, args) => {
+                serde_json::to_value(args)?
+            }
+            Command::GetChannels(args) => serde_json::to_value(args)?,
+            Command::SetVoiceSettings(args) => serde_json::to_value(args)?,
+            Command::SetUserVoiceSettings(args) => serde_json::to_value(args)?,
+            Command::SetActivity(args) => serde_json::to_value(args)?,
+            Command::SelectVoiceChannel(args) => serde_json::to_value(args)?,
+            Command::GetSelectedVoiceChannel | Command::GetGuilds | Command::GetVoiceSettings => {
+                return Ok(None)
+            }
+        };
+        Ok(Some(args))
+    }
+
+    /// Builds the json frame body that is written to the socket.
+    pub fn to_payload(&self, nonce: &str) -> Result<Value, serde_json::Error> {
+        let mut payload = json!({
+            "nonce": nonce,
+            "cmd": self.command_type().to_string(),
+        });
+        if let Some(args) = self.args()? {
+            payload["args"] = args;
+        }
+        if let Command::Subscribe(evt, _) | Command::Unsubscribe(evt, _) = self {
+            payload["evt"] = json!(evt.to_string());
+        }
+        Ok(payload)
+    }
+}
+
+// incoming ------------------------------------------------------------------
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    #[serde(default)]
+    pub avatar: Option<String>,
+    #[serde(default)]
+    pub global_name: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthorizeData {
+    pub code: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthenticateData {
+    pub user: User,
+    /// What the access token is good for.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AudioDevice {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VoiceIo {
+    pub device_id: String,
+    pub volume: f64,
+    #[serde(default)]
+    pub available_devices: Vec<AudioDevice>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum VoiceModeType {
+    PushToTalk,
+    VoiceActivity,
+}
+
+/// A key of a push to talk shortcut.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShortcutKeyCombo {
+    /// 0 keyboard key, 1 mouse button, 2 keyboard modifier key, 3 gamepad button.
+    #[serde(rename = "type")]
+    pub key_type: u8,
+    pub code: u32,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VoiceMode {
+    #[serde(rename = "type")]
+    pub mode_type: VoiceModeType,
+    pub auto_threshold: bool,
+    pub threshold: f64,
+    #[serde(default)]
+    pub shortcut: Vec<ShortcutKeyCombo>,
+    pub delay: f64,
+}
+
+/// The user's voice settings. Discord always sends all of them,
+/// only `mute` and `deaf` are required so partial replies still decode.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VoiceSettings {
+    pub mute: bool,
+    pub deaf: bool,
+    #[serde(default)]
+    pub input: Option<VoiceIo>,
+    #[serde(default)]
+    pub output: Option<VoiceIo>,
+    #[serde(default)]
+    pub mode: Option<VoiceMode>,
+    #[serde(default)]
+    pub automatic_gain_control: Option<bool>,
+    #[serde(default)]
+    pub echo_cancellation: Option<bool>,
+    #[serde(default)]
+    pub noise_suppression: Option<bool>,
+    #[serde(default)]
+    pub qos: Option<bool>,
+    #[serde(default)]
+    pub silence_warning: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VoiceState {
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub deaf: bool,
+    #[serde(default)]
+    pub self_mute: bool,
+    #[serde(default)]
+    pub self_deaf: bool,
+    #[serde(default)]
+    pub suppress: bool,
+}
+
+/// The volume of a user on the left and right channel, 0 to 1.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Pan {
+    pub left: f64,
+    pub right: f64,
+}
+
+impl Default for Pan {
+    fn default() -> Self {
+        Self {
+            left: 1.0,
+            right: 1.0,
+        }
+    }
+}
+
+fn default_volume() -> f64 {
+    100.0
+}
+
+/// Another user's voice settings, as we set them for us.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UserVoiceSettings {
+    pub user_id: String,
+    #[serde(default)]
+    pub pan: Pan,
+    #[serde(default = "default_volume")]
+    pub volume: f64,
+    #[serde(default)]
+    pub mute: bool,
+}
+
+/// A member of a voice channel, as found in `voice_states` and VOICE_STATE_* events.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VoiceStateData {
+    #[serde(default)]
+    pub nick: String,
+    /// Whether we muted the user for us, the server mute is in `voice_state`.
+    #[serde(default)]
+    pub mute: bool,
+    /// How loud we hear the user, 0 to 200.
+    #[serde(default = "default_volume")]
+    pub volume: f64,
+    #[serde(default)]
+    pub pan: Pan,
+    #[serde(default)]
+    pub voice_state: VoiceState,
+    pub user: User,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Channel {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub guild_id: Option<String>,
+    #[serde(default)]
+    pub voice_states: Vec<VoiceStateData>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Guild {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub icon_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GuildsData {
+    guilds: Vec<Guild>,
+}
+
+/// A channel of a guild, as listed by GET_CHANNELS.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuildChannel {
+    pub id: String,
+    pub name: String,
+    /// 2 for voice channels and 13 for stage channels.
+    #[serde(rename = "type")]
+    pub channel_type: u8,
+}
+
+impl GuildChannel {
+    pub fn is_voice(&self) -> bool {
+        matches!(self.channel_type, 2 | 13)
+    }
+}
+
+#[derive(Deserialize)]
+struct ChannelsData {
+    channels: Vec<GuildChannel>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct VoiceChannelSelectData {
+    pub channel_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum VoiceConnectionState {
+    Disconnected,
+    AwaitingEndpoint,
+    Authenticating,
+    Connecting,
+    Connected,
+    VoiceDisconnected,
+    VoiceConnecting,
+    VoiceConnected,
+    NoRoute,
+    IceChecking,
+    #[serde(other)]
+    Unknown,
+}
+
+/// Discord's pings are in milliseconds.
+#[derive(Deserialize, Clone, Debug)]
+pub struct VoiceConnectionStatusData {
+    pub state: VoiceConnectionState,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub average_ping: Option<f64>,
+    #[serde(default)]
+    pub last_ping: Option<f64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SpeakingData {
+    pub user_id: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ErrorData {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReadyConfig {
+    pub api_endpoint: String,
+}
+
+/// The answer to our handshake.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReadyData {
+    pub config: ReadyConfig,
+    #[serde(default)]
+    pub user: Option<User>,
+}
+
+/// Why discord closed the socket, sent with the CLOSE opcode.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CloseData {
+    pub code: i64,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl CloseData {
+    /// Reconnecting can't help with an invalid client id, origin, RPC version or encoding.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self.code, 4000 | 4001 | 4004 | 4005)
+    }
+}
+
+/// The reply to one of our commands, typed by the command it answers.
+/// Replies we only need to see arrive carry no data.
+#[derive(Clone, Debug)]
+pub enum Response {
+    Authorize(AuthorizeData),
+    Authenticate(AuthenticateData),
+    Subscribe,
+    Unsubscribe,
+    /// `None` when we are not in a voice channel.
+    GetSelectedVoiceChannel(Option<Channel>),
+    GetGuilds(Vec<Guild>),
+    GetChannels(Vec<GuildChannel>),
+    GetVoiceSettings(VoiceSettings),
+    /// The settings after the change.
+    SetVoiceSettings(VoiceSettings),
+    SetUserVoiceSettings(UserVoiceSettings),
+    SetActivity,
+    /// The channel we joined, `None` when we left.
+    SelectVoiceChannel(Option<Channel>),
+}
+
+impl Response {
+    fn from_frame(cmd: CommandType, data: Value) -> Result<Self, serde_json::Error> {
+        Ok(match cmd {
+            CommandType::Authorize => Response::Authorize(serde_json::from_value(data)?),
+            CommandType::Authenticate => Response::Authenticate(serde_json::from_value(data)?),
+            CommandType::Subscribe => Response::Subscribe,
+            CommandType::Unsubscribe => Response::Unsubscribe,
+            CommandType::GetSelectedVoiceChannel => {
+                Response::GetSelectedVoiceChannel(serde_json::from_value(data)?)
+            }
+            CommandType::GetGuilds => {
+                let data: GuildsData = serde_json::from_value(data)?;
+                Response::GetGuilds(data.guilds)
+            }
+            CommandType::GetChannels => {
+                let data: ChannelsData = serde_json::from_value(data)?;
+                Response::GetChannels(data.channels)
+            }
+            CommandType::GetVoiceSettings => {
+                Response::GetVoiceSettings(serde_json::from_value(data)?)
+            }
+            CommandType::SetVoiceSettings => {
+                Response::SetVoiceSettings(serde_json::from_value(data)?)
+            }
+            CommandType::SetUserVoiceSettings => {
+                Response::SetUserVoiceSettings(serde_json::from_value(data)?)
+            }
+            CommandType::SetActivity => Response::SetActivity,
+            CommandType::SelectVoiceChannel => {
+                Response::SelectVoiceChannel(serde_json::from_value(data)?)
+            }
+            CommandType::Dispatch => {
+                return Err(serde::de::Error::custom("DISPATCH is not a response"));
+            }
+        })
+    }
+}
+
+/// A DISPATCH frame for one of the events we subscribe to.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Ready,
+    VoiceSettingsUpdate(VoiceSettings),
+    VoiceChannelSelect(VoiceChannelSelectData),
+    VoiceStateCreate(VoiceStateData),
+    VoiceStateUpdate(VoiceStateData),
+    VoiceStateDelete(VoiceStateData),
+    SpeakingStart(SpeakingData),
+    SpeakingStop(SpeakingData),
+    VoiceConnectionStatus(VoiceConnectionStatusData),
+}
+
+impl Event {
+    fn from_frame(evt: EventType, data: Value) -> Result<Self, serde_json::Error> {
+        Ok(match evt {
+            EventType::Ready => Event::Ready,
+            EventType::VoiceSettingsUpdate => {
+                Event::VoiceSettingsUpdate(serde_json::from_value(data)?)
+            }
+            EventType::VoiceChannelSelect => {
+                Event::VoiceChannelSelect(serde_json::from_value(data)?)
+            }
+            EventType::VoiceStateCreate => Event::VoiceStateCreate(serde_json::from_value(data)?),
+            EventType::VoiceStateUpdate => Event::VoiceStateUpdate(serde_json::from_value(data)?),
+            EventType::VoiceStateDelete => Event::VoiceStateDelete(serde_json::from_value(data)?),
+            EventType::SpeakingStart => Event::SpeakingStart(serde_json::from_value(data)?),
+            EventType::SpeakingStop => Event::SpeakingStop(serde_json::from_value(data)?),
+            EventType::VoiceConnectionStatus => {
+                Event::VoiceConnectionStatus(serde_json::from_value(data)?)
+            }
+            EventType::Error => {
+                return Err(serde::de::Error::custom("ERROR is not a dispatch event"));
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Message {
+    Response {
+        nonce: Option<String>,
+        response: Response,
+    },
+    /// Discord rejected a command. `cmd` is `None` for commands we don't model.
+    Error {
+        nonce: Option<String>,
+        cmd: Option<CommandType>,
+        error: ErrorData,
+    },
+    Event(Event),
+    /// A frame for a command or event we don't model.
+    Unknown,
+}
+
+#[derive(Deserialize)]
+struct Frame {
+    cmd: String,
+    #[serde(default)]
+    evt: Option<String>,
+    #[serde(default)]
+    data: Value,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+impl Message {
+    pub fn from_payload(payload: Value) -> Result<Self, serde_json::Error> {
+        let frame: Frame = serde_json::from_value(payload)?;
+        let cmd = CommandType::from_str(&frame.cmd).ok();
+        let evt = frame.evt.as_deref().map(EventType::from_str);
+
+        match (cmd, evt) {
+            (cmd, Some(Ok(EventType::Error))) => Ok(Message::Error {
+                nonce: frame.nonce,
+                cmd,
+                error: serde_json::from_value(frame.data)?,
+            }),
+            (Some(CommandType::Dispatch), Some(Ok(evt))) => {
+                Ok(Message::Event(Event::from_frame(evt, frame.data)?))
+            }
+            (Some(CommandType::Dispatch), _) => Ok(Message::Unknown),
+            (Some(cmd), _) => Ok(Message::Response {
+                nonce: frame.nonce,
+                response: Response::from_frame(cmd, frame.data)?,
+            }),
+            (None, _) => Ok(Message::Unknown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribe_payload_puts_evt_at_top_level() {
+        let command = Command::Subscribe(
+            EventType::SpeakingStart,
+            SubscribeArgs {
+                channel_id: Some("300".to_string()),
+            },
+        );
+        assert_eq!(
+            command.to_payload("nonce").unwrap(),
+            json!({
+                "nonce": "nonce",
+                "cmd": "SUBSCRIBE",
+                "evt": "SPEAKING_START",
+                "args": { "channel_id": "300" }
+            })
+        );
+    }
+
+    #[test]
+    fn leaving_voice_channel_sends_null_channel_id() {
+        let command = Command::SelectVoiceChannel(SelectVoiceChannelArgs {
+            channel_id: None,
+            force: None,
+        });
+        let payload = command.to_payload("nonce").unwrap();
+        assert_eq!(payload["args"], json!({ "channel_id": null }));
+    }
+
+    #[test]
+    fn parses_error_frames() {
+        let message = Message::from_payload(json!({
+            "cmd": "AUTHORIZE",
+            "evt": "ERROR",
+            "data": { "code": 5000, "message": "OAuth2 Error: access_denied" },
+            "nonce": "nonce"
+        }))
+        .unwrap();
+        let Message::Error { cmd, error, nonce } = message else {
+            panic!("expected an error, got {message:?}");
+        };
+        assert_eq!(cmd, Some(CommandType::Authorize));
+        assert_eq!(error.code, 5000);
+        assert_eq!(nonce.as_deref(), Some("nonce"));
+    }
+
+    #[test]
+    fn guild_lists_are_unwrapped() {
+        let message = Message::from_payload(json!({
+            "cmd": "GET_GUILDS",
+            "evt": null,
+            "data": {
+                "guilds": [
+                    { "id": "100", "name": "home", "icon_url": "https://cdn.discordapp.com/icons/100/a.png" },
+                    { "id": "101", "name": "work", "icon_url": null }
+                ]
+            },
+            "nonce": "nonce"
+        }))
+        .unwrap();
+        let Message::Response {
+            response: Response::GetGuilds(guilds),
+            ..
+        } = message
+        else {
+            panic!("expected guilds, got {message:?}");
+        };
+        let names: Vec<_> = guilds.iter().map(|guild| guild.name.as_str()).collect();
+        assert_eq!(names, ["home", "work"]);
+        assert!(guilds[0].icon_url.is_some());
+        assert!(guilds[1].icon_url.is_none());
+    }
+
+    #[test]
+    fn channel_lists_are_unwrapped() {
+        let message = Message::from_payload(json!({
+            "cmd": "GET_CHANNELS",
+            "evt": null,
+            "data": {
+                "channels": [
+                    { "id": "300", "name": "general", "type": 0 },
+                    { "id": "301", "name": "standup", "type": 2 }
+                ]
+            },
+            "nonce": "nonce"
+        }))
+        .unwrap();
+        let Message::Response {
+            response: Response::GetChannels(channels),
+            ..
+        } = message
+        else {
+            panic!("expected channels, got {message:?}");
+        };
+        let voice: Vec<_> = channels.iter().filter(|c| c.is_voice()).collect();
+        assert_eq!(voice.len(), 1);
+        assert_eq!(voice[0].name, "standup");
+    }
+
+    #[test]
+    fn missing_fields_are_decode_errors() {
+        let result = Message::from_payload(json!({
+            "cmd": "GET_VOICE_SETTINGS",
+            "evt": null,
+            "data": { "mute": true },
+            "nonce": "nonce"
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn voice_settings_decode_every_field() {
+        let message = Message::from_payload(json!({
+            "cmd": "DISPATCH",
+            "evt": "VOICE_SETTINGS_UPDATE",
+            "data": {
+                "input": {
+                    "available_devices": [{ "id": "default", "name": "Default" }],
+                    "device_id": "default",
+                    "volume": 49.6
+                },
+                "output": { "available_devices": [], "device_id": "default", "volume": 93.0 },
+                "mode": {
+                    "type": "PUSH_TO_TALK",
+                    "auto_threshold": false,
+                    "threshold": -56.0,
+                    "shortcut": [{ "type": 0, "code": 12, "name": "i" }],
+                    "delay": 98.4
+                },
+                "automatic_gain_control": true,
+                "echo_cancellation": true,
+                "noise_suppression": false,
+                "qos": false,
+                "silence_warning": true,
+                "deaf": false,
+                "mute": false
+            },
+            "nonce": null
+        }))
+        .unwrap();
+        let Message::Event(Event::VoiceSettingsUpdate(settings)) = message else {
+            panic!("expected voice settings, got {message:?}");
+        };
+        let mode = settings.mode.unwrap();
+        assert_eq!(mode.mode_type, VoiceModeType::PushToTalk);
+        assert_eq!(mode.shortcut[0].name, "i");
+        assert_eq!(settings.input.unwrap().available_devices.len(), 1);
+        assert_eq!(settings.noise_suppression, Some(false));
+
+        let args = SetVoiceSettingsArgs {
+            output: Some(VoiceIoArgs {
+                volume: Some(120.0),
+                ..Default::default()
+            }),
+            mode: Some(VoiceModeArgs {
+                mode_type: Some(VoiceModeType::VoiceActivity),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(args).unwrap(),
+            json!({ "output": { "volume": 120.0 }, "mode": { "type": "VOICE_ACTIVITY" } })
+        );
+    }
+
+    #[test]
+    fn unknown_events_are_not_errors() {
+        let message = Message::from_payload(json!({
+            "cmd": "DISPATCH",
+            "evt": "NOTIFICATION_CREATE",
+            "data": {},
+            "nonce": null
+        }))
+        .unwrap();
+        assert!(matches!(message, Message::Unknown));
+    }
+}
@@ -0,0 +1,50 @@
+//! The OAuth2 scopes the app asks for, and which commands need which of them.
+
+use super::protocol::{Command, EventType};
+
+pub const RPC: &str = "rpc";
+pub const IDENTIFY: &str = "identify";
+pub const VOICE_READ: &str = "rpc.voice.read";
+pub const VOICE_WRITE: &str = "rpc.voice.write";
+pub const GUILDS: &str = "guilds";
+pub const ACTIVITIES_WRITE: &str = "rpc.activities.write";
+
+/// Enough for the voice channel view.
+pub const DEFAULT_SCOPES: [&str; 2] = [RPC, IDENTIFY];
+
+/// Asked for the first time our voice settings are read or changed.
+pub const VOICE_SCOPES: [&str; 2] = [VOICE_READ, VOICE_WRITE];
+
+pub fn default_scopes() -> Vec<String> {
+    DEFAULT_SCOPES.iter().map(|s| s.to_string()).collect()
+}
+
+/// The scope beyond `rpc` discord wants for the event.
+pub fn required_by_event(event: EventType) -> Option<&'static str> {
+    match event {
+        EventType::VoiceSettingsUpdate => Some(VOICE_READ),
+        _ => None,
+    }
+}
+
+/// The scope beyond `rpc` discord wants for the command.
+pub fn required_by(command: &Command) -> Option<&'static str> {
+    match command {
+        Command::GetVoiceSettings => Some(VOICE_READ),
+        Command::SetVoiceSettings(_)
+        | Command::SetUserVoiceSettings(_)
+        | Command::SelectVoiceChannel(_) => Some(VOICE_WRITE),
+        Command::GetGuilds | Command::GetChannels(_) => Some(GUILDS),
+        Command::SetActivity(_) => Some(ACTIVITIES_WRITE),
+        Command::Subscribe(event, _) => required_by_event(*event),
+        _ => None,
+    }
+}
+
+/// The first of `requested` that isn't in `granted`.
+pub fn first_missing<'a>(granted: &[String], requested: &'a [String]) -> Option<&'a str> {
+    requested
+        .iter()
+        .find(|s| !granted.contains(s))
+        .map(String::as_str)
+}
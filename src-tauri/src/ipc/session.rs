@@ -0,0 +1,1110 @@
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tauri::async_runtime::{spawn, Mutex};
+use tokio::{select, time::sleep};
+
+use crate::{
+    config::{get_stored_tokens, remember_user, save_tokens},
+    discord_api::api_client::TokenData,
+    event::{EventEmitter, EventName},
+    log::log_error,
+};
+
+use super::{
+    auth::{forget_tokens, AuthError, AuthErrorType},
+    client::{Connection, Events, IpcClient, IpcError, IpcErrorType},
+    protocol::{Command, Event, EventType, Message, Response, SubscribeArgs, User, VoiceStateData},
+    scope,
+    state::{ConnectionState, SharedConnectionState},
+    vc::{SharedVoiceChannelState, VoiceChannelState},
+};
+
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+// refresh the access token this long before it expires
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(10 * 60);
+const TOKEN_REFRESH_RETRY: Duration = Duration::from_secs(60);
+
+// subscribed once per session, not per voice channel
+const GLOBAL_EVENTS: [EventType; 2] = [
+    EventType::VoiceSettingsUpdate,
+    EventType::VoiceChannelSelect,
+];
+
+/// Why a session stopped.
+pub enum LoopExit {
+    /// The socket to discord died, so it is worth reconnecting.
+    Disconnected(String),
+    /// Something that was already reported as a critical error.
+    Fatal,
+}
+
+/// How a fresh connection gets its access token.
+enum Auth {
+    /// Tokens from the stored refresh token, the user doesn't have to do anything.
+    Tokens(TokenData),
+    /// Ask the user to authorize the app in discord.
+    Authorize,
+}
+
+/// Connection errors are worth a reconnect, anything else is reported as critical.
+/// When discord closed the socket itself, the user sees its reason either way.
+fn exit_with<E: EventEmitter>(err: IpcError, emitter: &E) -> LoopExit {
+    if let Some(close) = err.close_data() {
+        if !close.is_fatal() {
+            emitter.emit_event(EventName::Error, err.clone());
+            return LoopExit::Disconnected(err.message);
+        }
+    } else if err.is_connection_lost() {
+        return LoopExit::Disconnected(err.message);
+    }
+    emitter.emit_event(EventName::CriticalError, err);
+    LoopExit::Fatal
+}
+
+/// Reports an error of a single event. A closed connection is not reported,
+/// the session notices it by itself and reconnects.
+fn report<E: EventEmitter>(err: IpcError, emitter: &E) {
+    if !matches!(err.error_type, IpcErrorType::Connect | IpcErrorType::Closed) {
+        emitter.emit_event(EventName::Error, err);
+    }
+}
+
+/// Connects to discord, starts the authorization and spawns the session
+/// handling the events. A session that is already running is stopped first.
+/// `instance` is the socket of the discord client to use, any client when `None`.
+pub async fn connect<E: EventEmitter>(
+    emitter: E,
+    client: Arc<Mutex<IpcClient>>,
+    reauth: bool,
+    instance: Option<PathBuf>,
+) -> Result<(), IpcError> {
+    // only what the slow parts below need, so the client isn't locked meanwhile
+    let (state, credentials, opener) = {
+        let mut guard = client.lock().await;
+        guard.close();
+        guard.pin_instance(instance);
+        let state = guard.connection_state();
+        state.disconnect(&emitter);
+        state.transition(ConnectionState::Connecting, &emitter)?;
+        (state, guard.credentials(), guard.opener())
+    };
+
+    // reauth --------------------------------
+    let auth = if reauth {
+        match credentials.try_reauth().await {
+            Ok(t) => Auth::Tokens(t),
+            // no usable refresh token, only the user can get us new tokens
+            Err(err) if err.needs_authorization() => Auth::Authorize,
+            Err(err) => {
+                // the frontend falls back to the normal auth
+                state.disconnect(&emitter);
+                return Err(IpcError {
+                    error_type: IpcErrorType::ReAuth,
+                    message: format!("Failed to reauth.\n{}", err.message),
+                    payload: Some(json!({ "error_type": err.error_type })),
+                });
+            }
+        }
+    } else {
+        Auth::Authorize
+    };
+
+    // connect to ipc
+    let (connection, events) = match opener.open().await {
+        Ok(c) => c,
+        Err(err) => {
+            state.disconnect(&emitter);
+            return Err(err);
+        }
+    };
+
+    let mut guard = client.lock().await;
+    if state.get() != ConnectionState::Connecting {
+        // disconnected meanwhile
+        connection.close();
+        return Err(IpcError {
+            error_type: IpcErrorType::Connect,
+            message: "The connection was closed while connecting.".to_string(),
+            payload: None,
+        });
+    }
+    guard.set_connection(Arc::clone(&connection));
+
+    // subscribe and emit events
+    let session = spawn(supervise(
+        connection,
+        events,
+        auth,
+        Arc::clone(&client),
+        emitter,
+    ));
+    guard.set_session(session);
+    Ok(())
+}
+
+/// Delay before the given reconnect attempt: 1s, 2s, 4s, ... up to a minute.
+fn backoff_delay(attempt: u32) -> Duration {
+    let delay = RECONNECT_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+    delay.min(RECONNECT_MAX_DELAY)
+}
+
+/// Runs the session and reconnects whenever discord drops the socket,
+/// e.g. because the discord client was restarted.
+async fn supervise<E: EventEmitter>(
+    mut connection: Arc<Connection>,
+    mut events: Events,
+    mut auth: Auth,
+    client: Arc<Mutex<IpcClient>>,
+    emitter: E,
+) {
+    let state = client.lock().await.connection_state();
+    loop {
+        match run_session(&connection, &mut events, auth, &client, &state, &emitter).await {
+            LoopExit::Fatal => {
+                // nothing will use the connection anymore
+                connection.close();
+                state.disconnect(&emitter);
+                break;
+            }
+            LoopExit::Disconnected(reason) => {
+                log_error(
+                    "ipc".to_string(),
+                    format!("Lost the connection to discord.\n{reason}"),
+                );
+                state.disconnect(&emitter);
+                (connection, events, auth) = reconnect(&client, &state, &emitter).await;
+            }
+        }
+    }
+}
+
+/// Retries until discord accepts the socket again. The session is then
+/// authenticated with the stored refresh token, or authorized again
+/// when discord rejected the refresh token. Other reauth failures are retried.
+async fn reconnect<E: EventEmitter>(
+    client: &Mutex<IpcClient>,
+    state: &SharedConnectionState,
+    emitter: &E,
+) -> (Arc<Connection>, Events, Auth) {
+    // always allowed once the session is disconnected
+    let _ = state.transition(ConnectionState::Connecting, emitter);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let delay = backoff_delay(attempt);
+        emitter.emit_event(
+            EventName::Reconnecting,
+            json!({
+                "attempt": attempt,
+                "delay_ms": delay.as_millis() as u64
+            }),
+        );
+        sleep(delay).await;
+
+        let (opener, credentials) = {
+            let guard = client.lock().await;
+            (guard.opener(), guard.credentials())
+        };
+        let Ok((connection, events)) = opener.open().await else {
+            // discord is not back yet
+            continue;
+        };
+        client.lock().await.set_connection(Arc::clone(&connection));
+        let auth = match credentials.try_reauth().await {
+            Ok(t) => Auth::Tokens(t),
+            // no usable refresh token, only the user can get us new tokens
+            Err(err) if err.needs_authorization() => Auth::Authorize,
+            Err(err) => {
+                // e.g. the token endpoint is unreachable, the refresh token may still be fine
+                log_error(
+                    "ipc".to_string(),
+                    format!("Could not reauth after reconnecting.\n{}", err.message),
+                );
+                connection.close();
+                continue;
+            }
+        };
+        emitter.emit_event(EventName::Reconnected, json!({ "attempts": attempt }));
+        return (connection, events, auth);
+    }
+}
+
+/// Gets an access token for the connection and authenticates it.
+async fn authenticate<E: EventEmitter>(
+    connection: &Connection,
+    auth: Auth,
+    client: &Mutex<IpcClient>,
+    state: &SharedConnectionState,
+    emitter: &E,
+) -> Result<(User, TokenData), LoopExit> {
+    let tokens = match auth {
+        Auth::Tokens(t) => t,
+        Auth::Authorize => {
+            // this flow is only used in initial authentication or reauth failed
+            if let Err(err) = state.transition(ConnectionState::Authorizing, emitter) {
+                return Err(exit_with(err, emitter));
+            }
+            let (pkce, scopes) = {
+                let guard = client.lock().await;
+                (guard.api_client.pkce(), guard.scopes().to_vec())
+            };
+            let code = match connection.authorize(pkce.as_ref(), &scopes).await {
+                Ok(c) => c,
+                Err(err) => return Err(exit_with(err, emitter)),
+            };
+            // fetch access token, without keeping the client locked meanwhile
+            let api_client = Arc::clone(&client.lock().await.api_client);
+            match api_client.fetch_discord_token(&code, pkce.as_ref()).await {
+                Ok(t) => t,
+                Err(err) => {
+                    emitter.emit_event(EventName::CriticalError, err);
+                    return Err(LoopExit::Fatal);
+                }
+            }
+        }
+    };
+    let profile = client.lock().await.profile().to_string();
+    store_tokens(&profile, &tokens, emitter);
+    // send token to ipc
+    if let Err(err) = state.transition(ConnectionState::Authenticating, emitter) {
+        return Err(exit_with(err, emitter));
+    }
+    let user = match connection.authenticate(tokens.access_token.clone()).await {
+        Ok(data) => data.user,
+        Err(err) => return Err(exit_with(err, emitter)),
+    };
+    // only used to tell the profiles apart
+    if let Err(err) = remember_user(&profile, &user) {
+        emitter.emit_event(EventName::Error, err);
+    }
+    Ok((user, tokens))
+}
+
+/// Keeps the refresh token for the next start. Failing that only costs
+/// the user another authorization later, so it is not critical.
+fn store_tokens<E: EventEmitter>(profile: &str, tokens: &TokenData, emitter: &E) {
+    if let Err(err) = save_tokens(profile, tokens) {
+        emitter.emit_event(
+            EventName::Error,
+            AuthError {
+                error_type: AuthErrorType::ConfigSave,
+                message: err.to_string(),
+            },
+        );
+    }
+}
+
+/// Asks the user to grant the scopes the connection is missing and authenticates
+/// it with the new tokens. This way the voice scopes are only asked for once used.
+pub async fn grant_scopes<E: EventEmitter>(
+    connection: &Connection,
+    client: &Mutex<IpcClient>,
+    scopes: &[&str],
+    emitter: &E,
+) -> Result<(), IpcError> {
+    let _turn = connection.grant_turn.lock().await;
+    let missing: Vec<&str> = scopes
+        .iter()
+        .copied()
+        .filter(|scope| !connection.has_scope(scope))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let (pkce, mut requested, profile, api_client) = {
+        let guard = client.lock().await;
+        (
+            guard.api_client.pkce(),
+            guard.scopes().to_vec(),
+            guard.profile().to_string(),
+            Arc::clone(&guard.api_client),
+        )
+    };
+    for scope in &missing {
+        if !requested.iter().any(|s| s == scope) {
+            requested.push(scope.to_string());
+        }
+    }
+    let code = connection.authorize(pkce.as_ref(), &requested).await?;
+    let tokens = api_client
+        .fetch_discord_token(&code, pkce.as_ref())
+        .await
+        .map_err(|err| IpcError {
+            error_type: IpcErrorType::ReAuth,
+            message: format!("Failed to get tokens for the new scopes.\n{}", err.message),
+            payload: Some(json!({ "error_type": err.error_type })),
+        })?;
+    store_tokens(&profile, &tokens, emitter);
+    connection.authenticate(tokens.access_token).await?;
+
+    // what the session left out for lack of the scopes
+    for event in GLOBAL_EVENTS {
+        if scope::required_by_event(event).is_some_and(|scope| missing.contains(&scope)) {
+            connection
+                .subscribe(event, SubscribeArgs::default(), true)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// When to refresh the access token: a bit before it expires,
+/// or halfway through when it doesn't live much longer than that.
+fn refresh_time(tokens: &TokenData) -> SystemTime {
+    let now = SystemTime::now();
+    let lifetime = tokens.expires_at.duration_since(now).unwrap_or_default();
+    now + lifetime
+        .saturating_sub(TOKEN_REFRESH_MARGIN)
+        .max(lifetime / 2)
+}
+
+/// Gets a new access token and authenticates the connection with it,
+/// so the session never runs on an expired token.
+/// `None` when discord rejected the refresh token, the next connection
+/// authorizes again then and there is nothing to retry.
+async fn refresh_tokens<E: EventEmitter>(
+    connection: &Connection,
+    tokens: &TokenData,
+    client: &Mutex<IpcClient>,
+    emitter: &E,
+) -> Result<Option<TokenData>, IpcError> {
+    let (profile, api_client) = {
+        let guard = client.lock().await;
+        (guard.profile().to_string(), Arc::clone(&guard.api_client))
+    };
+    // newer than ours when more scopes were granted meanwhile
+    let refresh_token = match get_stored_tokens(&profile) {
+        Ok(Some(stored)) => stored.refresh_token,
+        _ => tokens.refresh_token.clone(),
+    };
+    let refreshed = api_client.refresh_discord_token(refresh_token).await;
+    let tokens = match refreshed {
+        Ok(t) => t,
+        Err(err) if err.needs_authorization() => {
+            forget_tokens(&profile);
+            emitter.emit_event(EventName::Error, err);
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(IpcError {
+                error_type: IpcErrorType::ReAuth,
+                message: format!("Failed to refresh the access token.\n{}", err.message),
+                payload: None,
+            });
+        }
+    };
+    store_tokens(&profile, &tokens, emitter);
+    connection.authenticate(tokens.access_token.clone()).await?;
+
+    let expires_at = match tokens.expires_at.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    emitter.emit_event(
+        EventName::TokenRefreshed,
+        json!({
+            "expires_at": expires_at,
+            "scopes": tokens.scopes
+        }),
+    );
+    Ok(Some(tokens))
+}
+
+/// Asks discord which voice channel we are in and follows its events.
+async fn follow_selected_channel<E: EventEmitter>(
+    connection: &Connection,
+    vc_state: &SharedVoiceChannelState,
+    state: &SharedConnectionState,
+    emitter: &E,
+) -> Result<(), IpcError> {
+    let response = connection.send(Command::GetSelectedVoiceChannel).await?;
+    let Response::GetSelectedVoiceChannel(channel) = response else {
+        return Err(IpcError::unexpected_response(response));
+    };
+    match channel {
+        None => {
+            // not currently in vc
+            vc_state.lock().unwrap().leave();
+            state.transition(ConnectionState::Ready, emitter)?;
+            emitter.emit_event(EventName::VCSelect, json!({ "in_vc": false }));
+        }
+        Some(channel) => {
+            // in vc
+            vc_state.lock().unwrap().join(&channel);
+            state.transition(ConnectionState::InVoice, emitter)?;
+            emitter.emit_event(EventName::VCSelect, json!({ "in_vc": true }));
+            emitter.emit_event(
+                EventName::VCInfo,
+                json!({
+                    "name": channel.name,
+                    "users": channel.voice_states
+                }),
+            );
+
+            if let Err(err) = connection.set_vc_events(&channel.id, true).await {
+                report(err, emitter);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Authenticates a fresh connection and handles its events until it dies.
+async fn run_session<E: EventEmitter>(
+    connection: &Connection,
+    events: &mut Events,
+    auth: Auth,
+    client: &Mutex<IpcClient>,
+    state: &SharedConnectionState,
+    emitter: &E,
+) -> LoopExit {
+    // nothing of the previous connection is known to be true anymore
+    let (vc_state, voice_thresholds) = {
+        let guard = client.lock().await;
+        (guard.vc_state(), guard.voice_thresholds().clone())
+    };
+    *vc_state.lock().unwrap() = VoiceChannelState::default();
+
+    let (user, mut tokens) = match authenticate(connection, auth, client, state, emitter).await {
+        Ok(a) => a,
+        Err(exit) => return exit,
+    };
+    let mut refresh_at = Some(refresh_time(&tokens));
+    if let Err(err) = state.transition(ConnectionState::Ready, emitter) {
+        return exit_with(err, emitter);
+    }
+    vc_state.lock().unwrap().user_id = Some(user.id.clone());
+    emitter.emit_event(EventName::UserID, user.id);
+
+    // subscribe events after authentication was done
+    for event in GLOBAL_EVENTS {
+        // a missing scope is reported rather than ending the session,
+        // `grant_scopes` subscribes once the user granted it
+        match connection
+            .subscribe(event, SubscribeArgs::default(), true)
+            .await
+        {
+            Ok(_) => {}
+            Err(err) if err.is_connection_lost() => return exit_with(err, emitter),
+            Err(err) => report(err, emitter),
+        }
+    }
+    // get the current voice channel
+    match follow_selected_channel(connection, &vc_state, state, emitter).await {
+        Ok(_) => {}
+        Err(err) if err.is_connection_lost() => return exit_with(err, emitter),
+        Err(err) => report(err, emitter),
+    }
+
+    loop {
+        let refresh_in = refresh_at
+            .and_then(|at| at.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default();
+        let message = select! {
+            message = events.recv() => message,
+            _ = sleep(refresh_in), if refresh_at.is_some() => {
+                match refresh_tokens(connection, &tokens, client, emitter).await {
+                    Ok(Some(t)) => {
+                        tokens = t;
+                        refresh_at = Some(refresh_time(&tokens));
+                    }
+                    Ok(None) => refresh_at = None,
+                    Err(err) if err.is_connection_lost() => return exit_with(err, emitter),
+                    Err(err) => {
+                        // the current token keeps working until it expires
+                        emitter.emit_event(EventName::Error, err);
+                        refresh_at = Some(SystemTime::now() + TOKEN_REFRESH_RETRY);
+                    }
+                }
+                continue;
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let message = match message {
+            Ok(m) => m,
+            Err(err) if err.is_connection_lost() => return exit_with(err, emitter),
+            Err(err) => {
+                emitter.emit_event(EventName::Error, err);
+                continue;
+            }
+        };
+        match message {
+            Message::Event(Event::VoiceSettingsUpdate(settings)) => {
+                // vc settings update event
+                vc_state.lock().unwrap().set_voice_settings(&settings);
+                emitter.emit_event(
+                    EventName::VCMuteUpdate,
+                    json!({
+                        "mute": settings.mute,
+                        "deaf": settings.deaf,
+                    }),
+                );
+                emitter.emit_event(EventName::VoiceSettings, settings);
+            }
+            Message::Event(Event::VoiceChannelSelect(data)) => {
+                // vc select update event
+                // unsubscribe events of the channel we were in
+                let previous = vc_state.lock().unwrap().leave();
+                if let Some(channel_id) = previous {
+                    if let Err(err) = connection.set_vc_events(&channel_id, false).await {
+                        report(err, emitter);
+                    }
+                }
+                if let Err(err) = state.transition(ConnectionState::Ready, emitter) {
+                    report(err, emitter);
+                }
+                match data.channel_id {
+                    None => {
+                        // left vc
+                        emitter.emit_event(EventName::VCSelect, json!({ "in_vc": false }));
+                    }
+                    Some(_) => {
+                        // joined vc
+                        emitter.emit_event(EventName::VCSelect, json!({ "in_vc": true }));
+                        if let Err(err) =
+                            follow_selected_channel(connection, &vc_state, state, emitter).await
+                        {
+                            report(err, emitter);
+                        }
+                    }
+                }
+            }
+            Message::Event(Event::VoiceStateCreate(data)) => {
+                // someone joined vc
+                let is_me = {
+                    let mut vc = vc_state.lock().unwrap();
+                    vc.upsert_member(&data);
+                    vc.is_me(&data.user.id)
+                };
+                if !is_me {
+                    emitter.emit_event(EventName::VCUser, vc_user_payload("JOIN", &data));
+                }
+            }
+            Message::Event(Event::VoiceStateUpdate(data)) => {
+                let is_me = {
+                    let mut vc = vc_state.lock().unwrap();
+                    vc.upsert_member(&data);
+                    vc.is_me(&data.user.id)
+                };
+                if !is_me {
+                    emitter.emit_event(EventName::VCUser, vc_user_payload("UPDATE", &data));
+                }
+            }
+            Message::Event(Event::VoiceStateDelete(data)) => {
+                let is_me = {
+                    let mut vc = vc_state.lock().unwrap();
+                    vc.remove_member(&data.user.id);
+                    vc.is_me(&data.user.id)
+                };
+                if !is_me {
+                    emitter.emit_event(
+                        EventName::VCUser,
+                        json!({
+                            "event": "LEAVE",
+                            "data": {
+                                "id": data.user.id
+                            }
+                        }),
+                    );
+                }
+            }
+            Message::Event(Event::SpeakingStart(data)) => {
+                let is_me = {
+                    let mut vc = vc_state.lock().unwrap();
+                    vc.set_speaking(&data.user_id, true);
+                    vc.is_me(&data.user_id)
+                };
+                emitter.emit_event(
+                    EventName::VCSpeak,
+                    json!({
+                        "user_id": data.user_id,
+                        "is_me": is_me,
+                        "speaking": true
+                    }),
+                );
+            }
+            Message::Event(Event::SpeakingStop(data)) => {
+                let is_me = {
+                    let mut vc = vc_state.lock().unwrap();
+                    vc.set_speaking(&data.user_id, false);
+                    vc.is_me(&data.user_id)
+                };
+                emitter.emit_event(
+                    EventName::VCSpeak,
+                    json!({
+                        "user_id": data.user_id,
+                        "is_me": is_me,
+                        "speaking": false
+                    }),
+                );
+            }
+            Message::Event(Event::VoiceConnectionStatus(status)) => {
+                let (voice_connection, warnings) = {
+                    let mut vc = vc_state.lock().unwrap();
+                    // a late one from the channel we left
+                    if vc.channel.is_none() {
+                        continue;
+                    }
+                    let warnings = vc.voice_connection.update(&status, &voice_thresholds);
+                    (vc.voice_connection.clone(), warnings)
+                };
+                emitter.emit_event(EventName::VCConnection, voice_connection);
+                for warning in warnings {
+                    emitter.emit_event(EventName::VCConnectionWarning, warning);
+                }
+            }
+            Message::Error { cmd, error, .. } => {
+                // an error that doesn't answer one of our requests
+                let cmd = cmd.map(|c| c.to_string()).unwrap_or_default();
+                emitter.emit_event(
+                    EventName::Error,
+                    IpcError {
+                        error_type: IpcErrorType::EventReceive,
+                        message: format!(
+                            "Discord reported an error.\n{} ({})",
+                            error.message, error.code
+                        ),
+                        payload: Some(json!({ "cmd": cmd })),
+                    },
+                );
+            }
+            // our own voice state and events we don't model
+            _ => {}
+        }
+    }
+    LoopExit::Disconnected("The event channel was closed.".to_string())
+}
+
+fn vc_user_payload(event: &str, data: &VoiceStateData) -> Value {
+    json!({
+        "event": event,
+        "data": {
+            "id": data.user.id,
+            "username": data.user.username,
+            "avatar": data.user.avatar,
+            "nick": data.nick,
+            "mute": data.voice_state.mute,
+            "self_mute": data.voice_state.self_mute,
+            "deaf": data.voice_state.deaf,
+            "self_deaf": data.voice_state.self_deaf,
+            "local_mute": data.mute,
+            "volume": data.volume,
+            "pan": data.pan,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{
+        discord_api::{
+            api_client::DiscordAPIClient,
+            exchanger::{OAuthEndpoint, PublicClientExchanger},
+        },
+        ipc::mock::{MockDiscord, RecordingEmitter, Script},
+    };
+    use dotenvy_macro::dotenv;
+    use tauri::async_runtime::block_on;
+
+    fn new_client() -> Arc<Mutex<IpcClient>> {
+        Arc::new(Mutex::new(IpcClient::new()))
+    }
+
+    /// Runs a session on a fresh connection, authenticated with stored tokens.
+    fn start_session(
+        client: &Arc<Mutex<IpcClient>>,
+        emitter: &RecordingEmitter,
+    ) -> SharedConnectionState {
+        let connection_state = block_on(client.lock()).connection_state();
+        connection_state
+            .transition(ConnectionState::Connecting, emitter)
+            .map_err(|err| err.message)
+            .unwrap();
+        let (connection, events) = block_on(async { client.lock().await.connect().await })
+            .map_err(|err| err.message)
+            .unwrap();
+        let tokens = TokenData {
+            access_token: "mock-access-token".to_string(),
+            refresh_token: "mock-refresh-token".to_string(),
+            expires_at: SystemTime::now() + Duration::from_secs(7 * 24 * 60 * 60),
+            scopes: vec!["rpc".to_string(), "identify".to_string()],
+        };
+        let session = spawn(supervise(
+            connection,
+            events,
+            Auth::Tokens(tokens),
+            Arc::clone(client),
+            emitter.clone(),
+        ));
+        block_on(client.lock()).set_session(session);
+        connection_state
+    }
+
+    #[test]
+    fn connect_requests_authorization() {
+        let discord = MockDiscord::start(Script::new());
+        let emitter = RecordingEmitter::default();
+
+        let client = new_client();
+
+        block_on(connect(emitter, Arc::clone(&client), false, None))
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let authorize = discord.wait_for_command("AUTHORIZE");
+        assert_eq!(authorize["args"]["client_id"], dotenv!("CLIENT_ID"));
+        assert_eq!(authorize["args"]["scopes"], json!(["rpc", "identify"]));
+        assert!(authorize["args"].get("code_challenge").is_none());
+        block_on(client.lock()).close();
+    }
+
+    #[test]
+    fn voice_scopes_are_asked_for_when_used() {
+        let discord = MockDiscord::start(
+            Script::new()
+                .reply(
+                    "AUTHENTICATE",
+                    json!({
+                        "user": { "id": "100", "username": "me", "avatar": null },
+                        "scopes": ["rpc", "identify"]
+                    }),
+                )
+                .ignore("AUTHORIZE"),
+        );
+        let emitter = RecordingEmitter::default();
+        let client = new_client();
+        let (connection, _events) = block_on(async { client.lock().await.connect().await })
+            .map_err(|err| err.message)
+            .unwrap();
+        block_on(connection.authenticate("token".to_string()))
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let grant = {
+            let client = Arc::clone(&client);
+            let connection = Arc::clone(&connection);
+            spawn(async move {
+                grant_scopes(&connection, &client, &scope::VOICE_SCOPES, &emitter).await
+            })
+        };
+        let authorize = discord.wait_for_command("AUTHORIZE");
+        assert_eq!(
+            authorize["args"]["scopes"],
+            json!(["rpc", "identify", "rpc.voice.read", "rpc.voice.write"])
+        );
+        // the client isn't locked while the user decides
+        drop(block_on(client.lock()));
+        block_on(client.lock()).close();
+        assert!(block_on(grant).unwrap().is_err());
+    }
+
+    #[test]
+    fn the_client_is_not_locked_while_connecting() {
+        // discord never answers the handshake
+        let _discord = MockDiscord::start(Script::new().ignore_handshake());
+        let emitter = RecordingEmitter::default();
+        let client = Arc::new(Mutex::new(
+            IpcClient::new().with_request_timeout(Duration::from_secs(1)),
+        ));
+
+        let connecting = spawn(connect(emitter, Arc::clone(&client), false, None));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(client.try_lock().is_ok());
+        assert!(block_on(connecting).unwrap().is_err());
+    }
+
+    #[test]
+    fn pkce_authorization_sends_a_challenge() {
+        let discord = MockDiscord::start(Script::new());
+        let emitter = RecordingEmitter::default();
+
+        let exchanger = PublicClientExchanger::new(OAuthEndpoint::default());
+        let api_client = DiscordAPIClient::with_exchanger(Box::new(exchanger), true);
+        let client = Arc::new(Mutex::new(IpcClient::new().with_api_client(api_client)));
+
+        block_on(connect(emitter, Arc::clone(&client), false, None))
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let authorize = discord.wait_for_command("AUTHORIZE");
+        assert_eq!(authorize["args"]["code_challenge_method"], "S256");
+        assert_eq!(
+            authorize["args"]["code_challenge"].as_str().unwrap().len(),
+            43
+        );
+        block_on(client.lock()).close();
+    }
+
+    #[test]
+    fn cancelled_authorization_is_critical() {
+        let _discord = MockDiscord::start(Script::new().error(
+            "AUTHORIZE",
+            5000,
+            "OAuth2 Error: access_denied: The resource owner or authorization server denied the request.",
+        ));
+        let emitter = RecordingEmitter::default();
+
+        block_on(connect(emitter.clone(), new_client(), false, None))
+            .map_err(|err| err.message)
+            .unwrap();
+
+        let error = emitter.wait_for("critical_error");
+        assert_eq!(error["error_type"], "Authorize");
+        emitter.wait_until("connection_state", |payload| {
+            payload == &json!({ "state": "Disconnected", "previous": "Authorizing" })
+        });
+    }
+
+    #[test]
+    fn authenticated_session_follows_voice_channel() {
+        let discord = MockDiscord::start(
+            Script::new()
+                .reply(
+                    "AUTHENTICATE",
+                    json!({
+                        "user": { "id": "100", "username": "me", "avatar": null },
+                        "scopes": ["rpc", "identify", "rpc.voice.read", "rpc.voice.write"],
+                        "expires": "2030-01-01T00:00:00.000Z"
+                    }),
+                )
+                .reply(
+                    "GET_SELECTED_VOICE_CHANNEL",
+                    json!({
+                        "id": "300",
+                        "guild_id": "400",
+                        "name": "standup",
+                        "type": 2,
+                        "voice_states": [{
+                            "nick": "teammate",
+                            "mute": false,
+                            "volume": 150,
+                            "voice_state": {
+                                "mute": false,
+                                "deaf": false,
+                                "self_mute": true,
+                                "self_deaf": false,
+                                "suppress": false
+                            },
+                            "user": { "id": "200", "username": "teammate", "avatar": null }
+                        }]
+                    }),
+                )
+                .dispatch(
+                    "GET_SELECTED_VOICE_CHANNEL",
+                    "VOICE_SETTINGS_UPDATE",
+                    json!({ "mute": true, "deaf": false }),
+                ),
+        );
+        let emitter = RecordingEmitter::default();
+
+        let client = new_client();
+        let connection_state = start_session(&client, &emitter);
+
+        let authenticate = discord.wait_for_command("AUTHENTICATE");
+        assert_eq!(authenticate["args"]["access_token"], "mock-access-token");
+
+        assert_eq!(emitter.wait_for("user_id"), json!("100"));
+        discord.wait_for_subscription("VOICE_SETTINGS_UPDATE");
+        discord.wait_for_subscription("VOICE_CHANNEL_SELECT");
+        assert_eq!(emitter.wait_for("vc_select"), json!({ "in_vc": true }));
+        emitter.wait_until("connection_state", |payload| {
+            payload == &json!({ "state": "InVoice", "previous": "Ready" })
+        });
+        let info = emitter.wait_for("vc_info");
+        assert_eq!(info["name"], "standup");
+        assert_eq!(info["users"][0]["user"]["id"], "200");
+        assert_eq!(
+            emitter.wait_for("vc_mute_update"),
+            json!({ "mute": true, "deaf": false })
+        );
+
+        discord.wait_for_subscription("SPEAKING_START");
+        discord.dispatch("SPEAKING_START", json!({ "user_id": "200" }));
+        assert_eq!(
+            emitter.wait_for("vc_speak"),
+            json!({ "user_id": "200", "is_me": false, "speaking": true })
+        );
+        let state = block_on(client.lock()).vc_state().lock().unwrap().clone();
+        assert_eq!(state.user_id.as_deref(), Some("100"));
+        assert_eq!(state.channel.unwrap().guild_id.as_deref(), Some("400"));
+        assert!(state.mute && !state.deaf);
+        let member = &state.members[0];
+        assert_eq!(member.id, "200");
+        assert!(member.self_mute && member.speaking);
+        // known from joining, before any VOICE_STATE event
+        assert_eq!(member.voice_settings().volume, 150.0);
+
+        discord.wait_for_subscription("VOICE_CONNECTION_STATUS");
+        discord.dispatch(
+            "VOICE_CONNECTION_STATUS",
+            json!({
+                "state": "VOICE_CONNECTED",
+                "hostname": "japan1234.discord.media",
+                "pings": [],
+                "average_ping": 420.5,
+                "last_ping": 430
+            }),
+        );
+        let status = emitter.wait_for("vc_connection");
+        assert_eq!(status["state"], "VOICE_CONNECTED");
+        assert_eq!(status["pings"], json!([430.0]));
+        assert_eq!(
+            emitter.wait_for("vc_connection_warning"),
+            json!({ "reason": "latency", "average_ping": 420.5, "threshold": 250.0 })
+        );
+
+        discord.wait_for_subscription("VOICE_STATE_DELETE");
+        discord.dispatch(
+            "VOICE_STATE_DELETE",
+            json!({
+                "nick": "teammate",
+                "voice_state": {},
+                "user": { "id": "200", "username": "teammate", "avatar": null }
+            }),
+        );
+        let left = emitter.wait_for("vc_user");
+        assert_eq!(left, json!({ "event": "LEAVE", "data": { "id": "200" } }));
+        assert!(block_on(client.lock())
+            .vc_state()
+            .lock()
+            .unwrap()
+            .members
+            .is_empty());
+
+        discord.dispatch(
+            "VOICE_CHANNEL_SELECT",
+            json!({ "channel_id": null, "guild_id": null }),
+        );
+        emitter.wait_until("vc_select", |payload| payload["in_vc"] == false);
+        assert_eq!(connection_state.get(), ConnectionState::Ready);
+        block_on(client.lock()).close();
+    }
+
+    #[test]
+    fn slow_replies_do_not_end_the_session() {
+        let discord = MockDiscord::start(
+            Script::new()
+                .reply(
+                    "AUTHENTICATE",
+                    json!({
+                        "user": { "id": "100", "username": "me", "avatar": null },
+                        "scopes": ["rpc", "identify", "rpc.voice.read", "rpc.voice.write"],
+                        "expires": "2030-01-01T00:00:00.000Z"
+                    }),
+                )
+                .ignore("GET_SELECTED_VOICE_CHANNEL"),
+        );
+        let emitter = RecordingEmitter::default();
+        let client = Arc::new(Mutex::new(
+            IpcClient::new().with_request_timeout(Duration::from_millis(200)),
+        ));
+        let connection_state = start_session(&client, &emitter);
+
+        let error = emitter.wait_for("error");
+        assert_eq!(error["error_type"], "Timeout");
+        // still on the same connection
+        discord.dispatch(
+            "VOICE_SETTINGS_UPDATE",
+            json!({ "mute": true, "deaf": false }),
+        );
+        emitter.wait_for("vc_mute_update");
+        assert_eq!(connection_state.get(), ConnectionState::Ready);
+        assert!(emitter.events("reconnecting").is_empty());
+        block_on(client.lock()).close();
+    }
+
+    #[test]
+    fn subscriptions_without_their_scope_are_reported() {
+        let discord = MockDiscord::start(Script::new().reply(
+            "AUTHENTICATE",
+            json!({
+                "user": { "id": "100", "username": "me", "avatar": null },
+                "scopes": ["rpc", "identify"],
+                "expires": "2030-01-01T00:00:00.000Z"
+            }),
+        ));
+        let emitter = RecordingEmitter::default();
+        let client = new_client();
+        start_session(&client, &emitter);
+
+        let error = emitter.wait_for("error");
+        assert_eq!(error["error_type"], "MissingScope");
+        assert_eq!(error["payload"]["scope"], "rpc.voice.read");
+        // the rest of the session goes on
+        discord.wait_for_subscription("VOICE_CHANNEL_SELECT");
+        assert!(discord
+            .received()
+            .iter()
+            .all(|payload| payload["evt"] != "VOICE_SETTINGS_UPDATE"));
+        block_on(client.lock()).close();
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff_delay(1), Duration::from_secs(1));
+        assert_eq!(backoff_delay(2), Duration::from_secs(2));
+        assert_eq!(backoff_delay(4), Duration::from_secs(8));
+        assert_eq!(backoff_delay(7), RECONNECT_MAX_DELAY);
+        assert_eq!(backoff_delay(u32::MAX), RECONNECT_MAX_DELAY);
+    }
+
+    #[test]
+    fn tokens_are_refreshed_before_they_expire() {
+        let tokens = |lifetime: Duration| TokenData {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            expires_at: SystemTime::now() + lifetime,
+            scopes: Vec::new(),
+        };
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        let refresh_in = |lifetime| {
+            refresh_time(&tokens(lifetime))
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        };
+
+        let in_a_week = refresh_in(week);
+        assert!(in_a_week < week - TOKEN_REFRESH_MARGIN + Duration::from_secs(1));
+        assert!(in_a_week > week - TOKEN_REFRESH_MARGIN - Duration::from_secs(5));
+        // short lived tokens are refreshed halfway through
+        let short = refresh_in(Duration::from_secs(60));
+        assert!(short <= Duration::from_secs(30) && short > Duration::from_secs(25));
+        assert_eq!(
+            refresh_in(Duration::ZERO),
+            Duration::ZERO,
+            "expired tokens are refreshed right away"
+        );
+    }
+
+    #[test]
+    fn lost_socket_starts_reconnecting() {
+        // the user hasn't answered the authorization yet
+        let discord = MockDiscord::start(Script::new().ignore("AUTHORIZE"));
+        let emitter = RecordingEmitter::default();
+
+        let client = new_client();
+
+        block_on(connect(emitter.clone(), Arc::clone(&client), false, None))
+            .map_err(|err| err.message)
+            .unwrap();
+        discord.wait_for_command("AUTHORIZE");
+
+        // discord quits
+        drop(discord);
+        let reconnecting = emitter.wait_for("reconnecting");
+        assert_eq!(
+            reconnecting,
+            json!({ "attempt": 1, "delay_ms": RECONNECT_BASE_DELAY.as_millis() as u64 })
+        );
+        block_on(client.lock()).close();
+    }
+}
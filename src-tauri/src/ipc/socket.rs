@@ -0,0 +1,237 @@
+//! The raw socket to the discord client and its frame format.
+//!
+//! Every frame is an 8 byte header (opcode and body length, both little endian u32)
+//! followed by a JSON body.
+
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use serde_json::Value;
+
+pub const OP_HANDSHAKE: u32 = 0;
+pub const OP_FRAME: u32 = 1;
+pub const OP_CLOSE: u32 = 2;
+pub const OP_PING: u32 = 3;
+pub const OP_PONG: u32 = 4;
+
+#[cfg(unix)]
+pub type Socket = std::os::unix::net::UnixStream;
+#[cfg(windows)]
+pub type Socket = std::fs::File;
+
+/// The half of an open socket the reader thread reads frames from.
+#[cfg(unix)]
+pub type Reader = Socket;
+/// The half of an open socket everyone else writes frames to.
+#[cfg(unix)]
+pub type Writer = Socket;
+#[cfg(windows)]
+pub use pipe::{Reader, Writer};
+
+#[cfg(unix)]
+pub fn connect(path: &Path) -> io::Result<Socket> {
+    Socket::connect(path)
+}
+
+#[cfg(windows)]
+pub fn connect(path: &Path) -> io::Result<Socket> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+}
+
+/// Splits the socket after the handshake, reads and writes may then happen at the same time.
+#[cfg(unix)]
+pub fn split(socket: Socket) -> io::Result<(Reader, Writer)> {
+    let reader = socket.try_clone()?;
+    Ok((reader, socket))
+}
+
+#[cfg(windows)]
+pub fn split(socket: Socket) -> io::Result<(Reader, Writer)> {
+    Ok(pipe::split(socket))
+}
+
+/// Stops the socket so a reader blocked on it wakes up.
+#[cfg(unix)]
+pub fn shutdown(writer: &Writer) {
+    let _ = writer.shutdown(std::net::Shutdown::Both);
+}
+
+/// The reader stops once it wrote out what was written before.
+#[cfg(windows)]
+pub fn shutdown(writer: &Writer) {
+    writer.shutdown();
+}
+
+/// Windows serializes synchronous I/O on a pipe, a write would wait for the
+/// read blocked on the other handle until discord sends something. So the
+/// reader owns the pipe and writes the frames queued by the writer itself,
+/// reading only once there is something to read.
+#[cfg(windows)]
+mod pipe {
+    use std::{
+        ffi::c_void,
+        fs::File,
+        io::{self, Read, Write},
+        os::windows::io::AsRawHandle,
+        ptr,
+        sync::mpsc::{self, RecvTimeoutError},
+        time::{Duration, Instant},
+    };
+
+    /// How long the reader waits for frames to write before it looks at the pipe again.
+    const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn PeekNamedPipe(
+            pipe: *mut c_void,
+            buffer: *mut c_void,
+            buffer_size: u32,
+            bytes_read: *mut u32,
+            total_bytes_avail: *mut u32,
+            bytes_left_this_message: *mut u32,
+        ) -> i32;
+    }
+
+    /// How many bytes can be read without blocking.
+    fn available(pipe: &File) -> io::Result<u32> {
+        let mut available = 0;
+        // SAFETY: the handle is open for as long as `pipe` lives,
+        // and every out pointer is either null or valid
+        let ok = unsafe {
+            PeekNamedPipe(
+                pipe.as_raw_handle() as *mut c_void,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                &mut available,
+                ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(available)
+    }
+
+    /// Waits until a frame header can be read without blocking.
+    pub fn wait_readable(pipe: &File, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while available(pipe)? < 8 {
+            if Instant::now() >= deadline {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    enum Outgoing {
+        Frame(Vec<u8>),
+        Shutdown,
+    }
+
+    pub fn split(pipe: File) -> (Reader, Writer) {
+        let (outgoing_tx, outgoing_rx) = mpsc::channel();
+        let reader = Reader {
+            pipe,
+            outgoing: outgoing_rx,
+            shut_down: false,
+        };
+        (reader, Writer(outgoing_tx))
+    }
+
+    pub struct Reader {
+        pipe: File,
+        outgoing: mpsc::Receiver<Outgoing>,
+        shut_down: bool,
+    }
+
+    impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                if self.shut_down {
+                    return Ok(0);
+                }
+                if available(&self.pipe)? > 0 {
+                    return self.pipe.read(buf);
+                }
+                match self.outgoing.recv_timeout(POLL_INTERVAL) {
+                    Ok(Outgoing::Frame(frame)) => self.pipe.write_all(&frame)?,
+                    Ok(Outgoing::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                        self.shut_down = true;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+            }
+        }
+    }
+
+    /// Queues what is written for the reader, a frame has to be written in one go.
+    pub struct Writer(mpsc::Sender<Outgoing>);
+
+    impl Writer {
+        pub fn shutdown(&self) {
+            let _ = self.0.send(Outgoing::Shutdown);
+        }
+    }
+
+    impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(Outgoing::Frame(buf.to_vec()))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+/// Reads a frame before the socket is split, failing with `TimedOut`
+/// when nothing arrives within `timeout`.
+#[cfg(unix)]
+pub fn read_frame_within(socket: &mut Socket, timeout: Duration) -> io::Result<(u32, Value)> {
+    socket.set_read_timeout(Some(timeout))?;
+    let frame = read_frame(socket).map_err(|err| match err.kind() {
+        io::ErrorKind::WouldBlock => io::Error::from(io::ErrorKind::TimedOut),
+        _ => err,
+    });
+    socket.set_read_timeout(None)?;
+    frame
+}
+
+#[cfg(windows)]
+pub fn read_frame_within(socket: &mut Socket, timeout: Duration) -> io::Result<(u32, Value)> {
+    pipe::wait_readable(socket, timeout)?;
+    read_frame(socket)
+}
+
+pub fn read_frame(socket: &mut impl Read) -> io::Result<(u32, Value)> {
+    let mut header = [0u8; 8];
+    socket.read_exact(&mut header)?;
+    let opcode = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut body = vec![0u8; length as usize];
+    socket.read_exact(&mut body)?;
+    let payload = serde_json::from_slice(&body)?;
+    Ok((opcode, payload))
+}
+
+/// Writes the frame with a single `write_all`.
+pub fn write_frame(socket: &mut impl Write, opcode: u32, payload: &Value) -> io::Result<()> {
+    let body = payload.to_string();
+    let mut frame = Vec::with_capacity(8 + body.len());
+    frame.extend_from_slice(&opcode.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(body.as_bytes());
+    socket.write_all(&frame)
+}
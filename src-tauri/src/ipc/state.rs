@@ -0,0 +1,113 @@
+//! Where the connection to discord is in its lifecycle.
+//!
+//! Disconnected → Connecting → Authorizing → Authenticating → Ready ⇄ InVoice,
+//! where Authorizing is skipped when a stored refresh token could be used
+//! and any state can drop back to Disconnected.
+
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::event::{EventEmitter, EventName};
+
+use super::client::{IpcError, IpcErrorType};
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    /// Opening the socket, or waiting for discord to come back.
+    Connecting,
+    /// Waiting for the user to authorize the app in discord.
+    Authorizing,
+    /// Sending the access token to discord.
+    Authenticating,
+    /// Authenticated and not in a voice channel.
+    Ready,
+    InVoice,
+}
+
+impl ConnectionState {
+    pub fn can_become(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (_, Disconnected)
+                | (Disconnected, Connecting)
+                | (Connecting, Authorizing | Authenticating)
+                | (Authorizing, Authenticating)
+                | (Authenticating, Ready)
+                | (Ready, InVoice)
+                | (InVoice, Ready)
+        )
+    }
+}
+
+/// The state shared by the client, the session and the commands.
+#[derive(Clone, Default)]
+pub struct SharedConnectionState(Arc<Mutex<ConnectionState>>);
+
+impl SharedConnectionState {
+    pub fn get(&self) -> ConnectionState {
+        *self.0.lock().unwrap()
+    }
+
+    /// Moves to `next` and emits the `connection_state` event.
+    /// Staying in the same state is not an error and emits nothing.
+    pub fn transition<E: EventEmitter>(
+        &self,
+        next: ConnectionState,
+        emitter: &E,
+    ) -> Result<(), IpcError> {
+        let previous = {
+            let mut state = self.0.lock().unwrap();
+            let previous = *state;
+            if previous == next {
+                return Ok(());
+            }
+            if !previous.can_become(next) {
+                return Err(IpcError {
+                    error_type: IpcErrorType::State,
+                    message: format!("Cannot go from {:?} to {:?}.", previous, next),
+                    payload: Some(json!({ "from": previous, "to": next })),
+                });
+            }
+            *state = next;
+            previous
+        };
+        emitter.emit_event(
+            EventName::ConnectionState,
+            json!({ "state": next, "previous": previous }),
+        );
+        Ok(())
+    }
+
+    /// Drops back to `Disconnected`, which every state can do.
+    pub fn disconnect<E: EventEmitter>(&self, emitter: &E) {
+        let _ = self.transition(ConnectionState::Disconnected, emitter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConnectionState::*;
+
+    #[test]
+    fn only_defined_transitions_are_allowed() {
+        assert!(Disconnected.can_become(Connecting));
+        assert!(Connecting.can_become(Authorizing));
+        assert!(Connecting.can_become(Authenticating));
+        assert!(Authorizing.can_become(Authenticating));
+        assert!(Authenticating.can_become(Ready));
+        assert!(Ready.can_become(InVoice));
+        assert!(InVoice.can_become(Ready));
+        assert!(InVoice.can_become(Disconnected));
+
+        assert!(!Disconnected.can_become(Ready));
+        assert!(!Connecting.can_become(InVoice));
+        assert!(!Authorizing.can_become(Ready));
+        assert!(!Ready.can_become(Authorizing));
+    }
+}
@@ -1,29 +1,287 @@
-use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
 
-use super::client::{IpcError, ReceiveIPCClient};
+use serde::Serialize;
 
-const VC_EVENTS: [&str; 5] = [
-    "VOICE_STATE_CREATE",
-    "VOICE_STATE_UPDATE",
-    "VOICE_STATE_DELETE",
-    "SPEAKING_START",
-    "SPEAKING_STOP",
+use super::{
+    client::{Connection, IpcError},
+    protocol::{
+        Channel, Command, EventType, Pan, Response, SetVoiceSettingsArgs, SubscribeArgs,
+        UserVoiceSettings, VoiceSettings, VoiceStateData,
+    },
+    voice_connection::VoiceConnection,
+};
+
+const VC_EVENTS: [EventType; 5] = [
+    EventType::VoiceStateCreate,
+    EventType::VoiceStateUpdate,
+    EventType::VoiceStateDelete,
+    EventType::SpeakingStart,
+    EventType::SpeakingStop,
 ];
 
-impl ReceiveIPCClient {
+/// Not tied to a channel, but only worth following while we are in one.
+const VC_GLOBAL_EVENTS: [EventType; 1] = [EventType::VoiceConnectionStatus];
+
+impl Connection {
     pub async fn set_vc_events(
-        &mut self,
-        channel_id: Value,
+        &self,
+        channel_id: &str,
         is_subscribe: bool,
     ) -> Result<(), IpcError> {
-        for event_name in VC_EVENTS {
-            if let Err(err) = self
-                .subscribe(event_name, json!({"channel_id": channel_id}), is_subscribe)
-                .await
-            {
-                return Err(err);
-            }
+        for event in VC_EVENTS {
+            let args = SubscribeArgs {
+                channel_id: Some(channel_id.to_string()),
+            };
+            self.subscribe(event, args, is_subscribe).await?;
+        }
+        for event in VC_GLOBAL_EVENTS {
+            self.subscribe(event, SubscribeArgs::default(), is_subscribe)
+                .await?;
         }
         Ok(())
     }
+
+    /// Asks discord for our voice settings and caches them.
+    pub async fn fetch_voice_settings(
+        &self,
+        vc_state: &SharedVoiceChannelState,
+    ) -> Result<VoiceSettings, IpcError> {
+        let response = self.send(Command::GetVoiceSettings).await?;
+        let Response::GetVoiceSettings(settings) = response else {
+            return Err(IpcError::unexpected_response(response));
+        };
+        vc_state.lock().unwrap().set_voice_settings(&settings);
+        Ok(settings)
+    }
+
+    /// Changes the given voice settings and caches what discord replies with.
+    pub async fn change_voice_settings(
+        &self,
+        vc_state: &SharedVoiceChannelState,
+        args: SetVoiceSettingsArgs,
+    ) -> Result<VoiceSettings, IpcError> {
+        let _turn = self.voice_settings_turn.lock().await;
+        self.send_voice_settings(vc_state, args).await
+    }
+
+    /// Changes the voice settings `change` derives from the cached ones,
+    /// fetching them first if nothing is cached yet. Nothing else changes them
+    /// meanwhile, so a second toggle sees the result of the first.
+    pub async fn update_voice_settings(
+        &self,
+        vc_state: &SharedVoiceChannelState,
+        change: impl FnOnce(&VoiceSettings) -> SetVoiceSettingsArgs,
+    ) -> Result<VoiceSettings, IpcError> {
+        let _turn = self.voice_settings_turn.lock().await;
+        let cached = vc_state.lock().unwrap().voice_settings.clone();
+        let current = match cached {
+            Some(settings) => settings,
+            None => self.fetch_voice_settings(vc_state).await?,
+        };
+        self.send_voice_settings(vc_state, change(&current)).await
+    }
+
+    async fn send_voice_settings(
+        &self,
+        vc_state: &SharedVoiceChannelState,
+        args: SetVoiceSettingsArgs,
+    ) -> Result<VoiceSettings, IpcError> {
+        let response = self.send(Command::SetVoiceSettings(args)).await?;
+        let Response::SetVoiceSettings(settings) = response else {
+            return Err(IpcError::unexpected_response(response));
+        };
+        vc_state.lock().unwrap().set_voice_settings(&settings);
+        Ok(settings)
+    }
+}
+
+/// A member of the voice channel we are in.
+#[derive(Serialize, Clone, Debug)]
+pub struct Member {
+    pub id: String,
+    pub username: String,
+    pub global_name: Option<String>,
+    pub avatar: Option<String>,
+    pub nick: String,
+    pub mute: bool,
+    pub deaf: bool,
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    pub speaking: bool,
+    /// Whether we muted the member for us.
+    pub local_mute: bool,
+    pub volume: f64,
+    pub pan: Pan,
+}
+
+impl Member {
+    fn from_voice_state(data: &VoiceStateData) -> Self {
+        Self {
+            id: data.user.id.clone(),
+            username: data.user.username.clone(),
+            global_name: data.user.global_name.clone(),
+            avatar: data.user.avatar.clone(),
+            nick: data.nick.clone(),
+            mute: data.voice_state.mute,
+            deaf: data.voice_state.deaf,
+            self_mute: data.voice_state.self_mute,
+            self_deaf: data.voice_state.self_deaf,
+            speaking: false,
+            local_mute: data.mute,
+            volume: data.volume,
+            pan: data.pan,
+        }
+    }
+
+    pub fn voice_settings(&self) -> UserVoiceSettings {
+        UserVoiceSettings {
+            user_id: self.id.clone(),
+            pan: self.pan,
+            volume: self.volume,
+            mute: self.local_mute,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct VoiceChannel {
+    pub id: String,
+    pub name: String,
+    pub guild_id: Option<String>,
+}
+
+/// Everything we know about the voice channel we are in,
+/// kept up to date by the session from discord's events.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct VoiceChannelState {
+    pub user_id: Option<String>,
+    /// `None` when we are not in a voice channel.
+    pub channel: Option<VoiceChannel>,
+    pub members: Vec<Member>,
+    // our own voice settings
+    pub mute: bool,
+    pub deaf: bool,
+    /// All of them, `None` until discord told us.
+    pub voice_settings: Option<VoiceSettings>,
+    pub voice_connection: VoiceConnection,
+}
+
+impl VoiceChannelState {
+    pub fn is_me(&self, user_id: &str) -> bool {
+        self.user_id.as_deref() == Some(user_id)
+    }
+
+    pub fn join(&mut self, channel: &Channel) {
+        self.channel = Some(VoiceChannel {
+            id: channel.id.clone(),
+            name: channel.name.clone(),
+            guild_id: channel.guild_id.clone(),
+        });
+        self.members = channel
+            .voice_states
+            .iter()
+            .map(Member::from_voice_state)
+            .collect();
+        self.voice_connection = VoiceConnection::default();
+    }
+
+    /// Forgets the channel, returning the id of the one we were in.
+    pub fn leave(&mut self) -> Option<String> {
+        self.members.clear();
+        self.voice_connection = VoiceConnection::default();
+        self.channel.take().map(|c| c.id)
+    }
+
+    /// Adds a member or updates its voice state, keeping whether it is speaking.
+    pub fn upsert_member(&mut self, data: &VoiceStateData) {
+        let mut member = Member::from_voice_state(data);
+        match self.members.iter_mut().find(|m| m.id == member.id) {
+            Some(existing) => {
+                member.speaking = existing.speaking;
+                *existing = member;
+            }
+            None => self.members.push(member),
+        }
+    }
+
+    pub fn remove_member(&mut self, user_id: &str) {
+        self.members.retain(|m| m.id != user_id);
+    }
+
+    pub fn set_speaking(&mut self, user_id: &str, speaking: bool) {
+        if let Some(member) = self.members.iter_mut().find(|m| m.id == user_id) {
+            member.speaking = speaking;
+        }
+    }
+
+    pub fn member(&self, user_id: &str) -> Option<&Member> {
+        self.members.iter().find(|m| m.id == user_id)
+    }
+
+    pub fn set_user_voice_settings(&mut self, settings: &UserVoiceSettings) {
+        if let Some(member) = self.members.iter_mut().find(|m| m.id == settings.user_id) {
+            member.local_mute = settings.mute;
+            member.volume = settings.volume;
+            member.pan = settings.pan;
+        }
+    }
+
+    pub fn set_voice_settings(&mut self, settings: &VoiceSettings) {
+        self.mute = settings.mute;
+        self.deaf = settings.deaf;
+        self.voice_settings = Some(settings.clone());
+    }
+}
+
+pub type SharedVoiceChannelState = Arc<Mutex<VoiceChannelState>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn voice_state(id: &str, self_mute: bool) -> VoiceStateData {
+        serde_json::from_value(json!({
+            "nick": id,
+            "voice_state": { "self_mute": self_mute },
+            "user": { "id": id, "username": id, "avatar": null }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn members_follow_voice_state_events() {
+        let mut state = VoiceChannelState::default();
+        state.join(&Channel {
+            id: "300".to_string(),
+            name: "standup".to_string(),
+            guild_id: Some("400".to_string()),
+            voice_states: vec![voice_state("100", false), voice_state("200", false)],
+        });
+        assert_eq!(state.channel.as_ref().unwrap().name, "standup");
+        assert_eq!(state.members.len(), 2);
+
+        state.set_speaking("200", true);
+        state.upsert_member(&voice_state("200", true));
+        let member = &state.members[1];
+        assert!(member.self_mute && member.speaking);
+        assert_eq!(member.volume, 100.0);
+
+        state.set_user_voice_settings(&UserVoiceSettings {
+            user_id: "200".to_string(),
+            pan: Pan::default(),
+            volume: 40.0,
+            mute: true,
+        });
+        let settings = state.member("200").unwrap().voice_settings();
+        assert!(settings.mute && settings.volume == 40.0);
+
+        state.upsert_member(&voice_state("300", false));
+        state.remove_member("100");
+        let ids: Vec<_> = state.members.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["200", "300"]);
+
+        assert_eq!(state.leave().as_deref(), Some("300"));
+        assert!(state.channel.is_none() && state.members.is_empty());
+    }
 }
@@ -0,0 +1,180 @@
+//! How well our voice connection is doing, from VOICE_CONNECTION_STATUS,
+//! to tell a bad connection on our side apart from an outage on discord's.
+
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+use super::protocol::{VoiceConnectionState, VoiceConnectionStatusData};
+
+pub const DEFAULT_PING_WARNING: Duration = Duration::from_millis(250);
+/// How many of the latest pings are kept.
+pub const PING_HISTORY_LEN: usize = 30;
+
+#[derive(Clone, Debug)]
+pub struct VoiceConnectionThresholds {
+    /// Warns once the average ping goes above this.
+    pub average_ping: Duration,
+}
+
+impl VoiceConnectionThresholds {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            average_ping: config.voice_ping_warning(),
+        }
+    }
+}
+
+impl Default for VoiceConnectionThresholds {
+    fn default() -> Self {
+        Self {
+            average_ping: DEFAULT_PING_WARNING,
+        }
+    }
+}
+
+/// Raised when the connection gets worse, not again until it recovered.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum VoiceConnectionWarning {
+    /// The connection dropped or found no route to the voice server.
+    State { state: VoiceConnectionState },
+    /// In milliseconds.
+    Latency { average_ping: f64, threshold: f64 },
+}
+
+/// What we know about the voice connection of the channel we are in.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct VoiceConnection {
+    /// `None` until discord reported it.
+    pub state: Option<VoiceConnectionState>,
+    pub hostname: Option<String>,
+    /// In milliseconds, as are the pings.
+    pub average_ping: Option<f64>,
+    pub last_ping: Option<f64>,
+    /// The latest pings, oldest first.
+    pub pings: VecDeque<f64>,
+    /// Whether a warning is still standing.
+    pub degraded: bool,
+    #[serde(skip)]
+    was_connected: bool,
+    #[serde(skip)]
+    bad_state: bool,
+    #[serde(skip)]
+    high_latency: bool,
+}
+
+impl VoiceConnection {
+    /// Takes in a status update, returning the warnings it newly raises.
+    pub fn update(
+        &mut self,
+        status: &VoiceConnectionStatusData,
+        thresholds: &VoiceConnectionThresholds,
+    ) -> Vec<VoiceConnectionWarning> {
+        self.state = Some(status.state);
+        self.hostname.clone_from(&status.hostname);
+        self.average_ping = status.average_ping;
+        self.last_ping = status.last_ping;
+        if let Some(ping) = status.last_ping {
+            if self.pings.len() == PING_HISTORY_LEN {
+                self.pings.pop_front();
+            }
+            self.pings.push_back(ping);
+        }
+
+        let mut warnings = Vec::new();
+        let bad_state = match status.state {
+            VoiceConnectionState::VoiceConnected => {
+                self.was_connected = true;
+                false
+            }
+            // also what leaving the channel looks like
+            VoiceConnectionState::Disconnected => false,
+            VoiceConnectionState::NoRoute => true,
+            // connecting again after having been connected
+            _ => self.was_connected,
+        };
+        if bad_state && !self.bad_state {
+            warnings.push(VoiceConnectionWarning::State {
+                state: status.state,
+            });
+        }
+        self.bad_state = bad_state;
+
+        let threshold = thresholds.average_ping.as_secs_f64() * 1000.0;
+        let high_latency = status.average_ping.is_some_and(|ping| ping > threshold);
+        if high_latency && !self.high_latency {
+            warnings.push(VoiceConnectionWarning::Latency {
+                average_ping: status.average_ping.unwrap_or_default(),
+                threshold,
+            });
+        }
+        self.high_latency = high_latency;
+
+        self.degraded = self.bad_state || self.high_latency;
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(state: VoiceConnectionState, ping: f64) -> VoiceConnectionStatusData {
+        VoiceConnectionStatusData {
+            state,
+            hostname: Some("japan1234.discord.media".to_string()),
+            average_ping: Some(ping),
+            last_ping: Some(ping),
+        }
+    }
+
+    #[test]
+    fn warnings_are_raised_once_per_degradation() {
+        let thresholds = VoiceConnectionThresholds::default();
+        let mut connection = VoiceConnection::default();
+
+        // joining goes through states that aren't problems yet
+        let connecting = status(VoiceConnectionState::VoiceConnecting, 0.0);
+        assert!(connection.update(&connecting, &thresholds).is_empty());
+        let connected = status(VoiceConnectionState::VoiceConnected, 40.0);
+        assert!(connection.update(&connected, &thresholds).is_empty());
+
+        let slow = status(VoiceConnectionState::VoiceConnected, 300.0);
+        assert_eq!(
+            connection.update(&slow, &thresholds),
+            [VoiceConnectionWarning::Latency {
+                average_ping: 300.0,
+                threshold: 250.0
+            }]
+        );
+        assert!(connection.update(&slow, &thresholds).is_empty());
+        assert!(connection.degraded);
+
+        assert_eq!(
+            connection.update(&connecting, &thresholds),
+            [VoiceConnectionWarning::State {
+                state: VoiceConnectionState::VoiceConnecting
+            }]
+        );
+        connection.update(&connected, &thresholds);
+        assert!(!connection.degraded);
+    }
+
+    #[test]
+    fn pings_are_kept_in_a_ring_buffer() {
+        let thresholds = VoiceConnectionThresholds::default();
+        let mut connection = VoiceConnection::default();
+        for ping in 0..PING_HISTORY_LEN + 5 {
+            connection.update(
+                &status(VoiceConnectionState::VoiceConnected, ping as f64),
+                &thresholds,
+            );
+        }
+        assert_eq!(connection.pings.len(), PING_HISTORY_LEN);
+        assert_eq!(connection.pings.front(), Some(&5.0));
+        assert_eq!(connection.last_ping, Some((PING_HISTORY_LEN + 4) as f64));
+    }
+}
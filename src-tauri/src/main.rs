@@ -3,61 +3,35 @@
 
 mod config;
 mod discord_api;
+mod event;
 mod ipc;
 mod log;
+mod secret;
 
+use config::{delete_tokens, get_config, update_config, Config, FavoriteChannel};
+use discord_api::api_client::DiscordAPIClient;
+use event::{EventEmitter, EventName};
 use ipc::{
     auth::{AuthError, AuthErrorType},
-    client::{IpcError, IpcErrorType, ReceiveIPCClient, SendIPCClient},
+    client::{Connection, IpcClient, IpcError, IpcErrorType},
+    discovery::{self, DiscordInstance},
+    protocol::{
+        Channel, Command, GetChannelsArgs, Guild, GuildChannel, Response, SelectVoiceChannelArgs,
+        SetActivityArgs, SetUserVoiceSettingsArgs, SetVoiceSettingsArgs, UserVoiceSettings,
+        VoiceSettings,
+    },
+    scope, session,
+    state::ConnectionState,
+    vc::VoiceChannelState,
+    voice_connection::VoiceConnectionThresholds,
 };
-use std::{process, sync::Arc};
-use tauri::async_runtime::Mutex;
-
-use config::save_refresh_token;
-use discord_rich_presence::{DiscordIpc, DiscordIpcClient};
-use dotenvy_macro::{self, dotenv};
-
 use log::log_error;
+use std::{path::PathBuf, process, sync::Arc};
+use tauri::async_runtime::{spawn_blocking, Mutex};
+
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use strum_macros::Display;
 use tauri::{Manager, State, Window};
-use uuid::Uuid;
-
-#[derive(Display)]
-enum EventName {
-    #[strum(to_string = "error")]
-    Error,
-    #[strum(to_string = "critical_error")]
-    CriticalError,
-    #[strum(to_string = "vc_select")]
-    VCSelect,
-    #[strum(to_string = "vc_info")]
-    VCInfo,
-    #[strum(to_string = "vc_mute_update")]
-    VCMuteUpdate,
-    #[strum(to_string = "vc_user")]
-    VCUser,
-    #[strum(to_string = "vc_speak")]
-    VCSpeak,
-    #[strum(to_string = "user_id")]
-    UserID,
-}
-
-fn emit_event<S: Serialize + Clone>(window: &Window, event_name: EventName, payload: S) -> () {
-    let event = event_name.to_string();
-    if let Err(err) = &window.emit(&event, payload) {
-        log_error(
-            "Emit Event Error".to_string(),
-            format!("Error while emitting {event_name} event.\n{err}"),
-        );
-    }
-}
-
-struct CurrentState {
-    channel_id: Value,
-    user_id: Value,
-}
 
 #[derive(Debug, Deserialize, Serialize)]
 struct TokenResponse {
@@ -66,638 +40,392 @@ struct TokenResponse {
     refresh_token: Option<String>,
 }
 
+/// Requests are sent through the connection, not the locked client,
+/// so commands don't wait for each other's replies.
+async fn connection(
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<Arc<Connection>, IpcError> {
+    client_manager.lock().await.connection()
+}
+
+/// The connection, once the user granted the scopes. They are only asked for
+/// when a command needs them, so users who never use the feature don't have to.
+async fn connection_with(
+    window: &Window,
+    client_manager: &State<'_, Arc<Mutex<IpcClient>>>,
+    scopes: &[&str],
+) -> Result<Arc<Connection>, IpcError> {
+    let connection = client_manager.lock().await.connection()?;
+    session::grant_scopes(&connection, client_manager, scopes, window).await?;
+    Ok(connection)
+}
+
 #[tauri::command]
 async fn connect_ipc(
     window: Window,
-    send_client_manager: State<'_, Arc<Mutex<SendIPCClient>>>,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
     reauth: bool,
+    instance: Option<String>,
 ) -> Result<(), IpcError> {
-    let client_id = dotenv!("CLIENT_ID");
-    let client = match DiscordIpcClient::new(client_id) {
-        Ok(c) => c,
-        Err(err) => {
-            return Err(IpcError {
-                error_type: IpcErrorType::CreateClient,
-                message: err.to_string(),
-                payload: None,
-            });
-        }
-    };
-    let mut receive_client = ReceiveIPCClient::new(client);
-
-    // reauth --------------------------------
-    if reauth {
-        let refreshed_data = match send_client_manager.lock().await.try_reauth().await {
-            Ok(t) => t,
-            Err(err) => {
-                // if the reauth failed, it tries to do the normal auth
-                return Err(IpcError {
-                    error_type: IpcErrorType::ReAuth,
-                    message: format!("Failed to reauth.\n{}", err.message),
-                    payload: None,
-                });
-            }
-        };
-
-        // connect to ipc
-        if let Err(err) = receive_client.ipc_client.connect() {
-            let _ = receive_client.ipc_client.close();
-            return Err(IpcError {
-                error_type: IpcErrorType::Connect,
-                message: err.to_string(),
-                payload: None,
-            });
-        }
-        if let Err(err) = send_client_manager.lock().await.ipc_client.connect() {
-            let _ = receive_client.ipc_client.close();
-            return Err(IpcError {
-                error_type: IpcErrorType::Connect,
-                message: err.to_string(),
-                payload: None,
-            });
-        }
-
-        if let Err(err) = receive_client
-            .send_token(refreshed_data.access_token.clone())
-            .await
-        {
-            let _ = receive_client.ipc_client.close();
-            return Err(IpcError {
-                error_type: IpcErrorType::Authorize,
-                message: err.message,
-                payload: None,
-            });
-        }
-
-        if let Err(err) = send_client_manager
-            .lock()
-            .await
-            .send_token(refreshed_data.access_token)
-            .await
-        {
-            let _ = receive_client.ipc_client.close();
-            return Err(IpcError {
-                error_type: IpcErrorType::Authorize,
-                message: err.message,
-                payload: None,
-            });
-        }
-
-        if let Err(err) = save_refresh_token(refreshed_data.refresh_token.to_string()) {
-            emit_event(
-                &window,
-                EventName::Error,
-                AuthError {
-                    error_type: AuthErrorType::ConfigSave,
-                    message: err.to_string(),
-                },
-            );
-        }
-    } else {
-        // connect to ipc
-        if let Err(err) = receive_client.ipc_client.connect() {
-            let _ = receive_client.ipc_client.close();
-            return Err(IpcError {
-                error_type: IpcErrorType::Connect,
-                message: err.to_string(),
-                payload: None,
-            });
-        }
-        if let Err(err) = send_client_manager.lock().await.ipc_client.connect() {
-            let _ = receive_client.ipc_client.close();
-            return Err(IpcError {
-                error_type: IpcErrorType::Connect,
-                message: err.to_string(),
-                payload: None,
-            });
-        }
-
-        if let Err(err) = receive_client.send_auth().await {
-            return Err(IpcError {
-                error_type: IpcErrorType::Authorize,
-                message: err.message,
-                payload: None,
-            });
-        }
-    }
-
-    let send_client = Arc::clone(&send_client_manager);
-    // subscribe and emit events
-    tauri::async_runtime::spawn(async move {
-        let mut current_state = CurrentState {
-            channel_id: Value::Null,
-            user_id: Value::Null,
-        };
-        loop {
-            let (_opcode, payload) = match receive_client.ipc_client.recv() {
-                Ok(res) => res,
-                Err(err) => {
-                    emit_event(&window, EventName::CriticalError, err.to_string());
-                    break;
-                }
-            };
-            println!("{}", payload);
-            if payload["evt"].is_null() {
-                if payload["cmd"] == "AUTHORIZE" {
-                    // authorization succeeded
-                    // this flow is only used in initial authentication or reauth failed
-                    let code = payload["data"]["code"].as_str().unwrap();
-                    // start authentication
-
-                    // fetch access token
-                    let tokens = match send_client
-                        .lock()
-                        .await
-                        .api_client
-                        .fetch_discord_token(code)
-                        .await
-                    {
-                        Ok(t) => t,
-                        Err(err) => {
-                            emit_event(&window, EventName::CriticalError, err);
-                            break;
-                        }
-                    };
-                    // save refresh token
-                    if let Err(err) = save_refresh_token(tokens.refresh_token) {
-                        emit_event(
-                            &window,
-                            EventName::Error,
-                            AuthError {
-                                error_type: AuthErrorType::ConfigSave,
-                                message: err.to_string(),
-                            },
-                        );
-                    }
-                    // send token to ipc
-                    if let Err(err) = receive_client.send_token(tokens.access_token.clone()).await {
-                        emit_event(&window, EventName::CriticalError, err);
-                        break;
-                    }
-                    if let Err(err) = send_client
-                        .lock()
-                        .await
-                        .send_token(tokens.access_token)
-                        .await
-                    {
-                        emit_event(&window, EventName::CriticalError, err);
-                        break;
-                    }
-                } else if payload["cmd"] == "AUTHENTICATE" {
-                    current_state.user_id = payload["data"]["user"]["id"].clone();
-                    emit_event(
-                        &window,
-                        EventName::UserID,
-                        payload["data"]["user"]["id"].clone(),
-                    );
-                    // subscribe events after authentication was done
-                    if let Err(err) = receive_client
-                        .subscribe("VOICE_SETTINGS_UPDATE", json!({}), true)
-                        .await
-                    {
-                        emit_event(
-                            &window,
-                            EventName::CriticalError,
-                            IpcError {
-                                error_type: err.error_type,
-                                message: err.message,
-                                payload: err.payload,
-                            },
-                        );
-                        break;
-                    }
-                    if let Err(err) = receive_client
-                        .subscribe("VOICE_CHANNEL_SELECT", json!({}), true)
-                        .await
-                    {
-                        emit_event(
-                            &window,
-                            EventName::CriticalError,
-                            IpcError {
-                                error_type: err.error_type,
-                                message: err.message,
-                                payload: err.payload,
-                            },
-                        );
-                        break;
-                    }
-                    // get the current voice channel
-                    let current_state_payload = json!({
-                        "nonce": Uuid::new_v4().to_string(),
-                        "cmd": "GET_SELECTED_VOICE_CHANNEL"
-                    });
-                    if let Err(err) = receive_client.send(current_state_payload).await {
-                        emit_event(&window, EventName::CriticalError, err);
-                        break;
-                    }
-                } else if payload["cmd"] == "GET_SELECTED_VOICE_CHANNEL" {
-                    if payload["data"].is_null() {
-                        // not currently in vc
-                        current_state.channel_id = Value::Null;
-                        emit_event(
-                            &window,
-                            EventName::VCSelect,
-                            json!({
-                                "in_vc": false
-                            }),
-                        );
-                    } else {
-                        // in vc
-                        current_state.channel_id = payload["data"]["id"].clone();
-                        emit_event(
-                            &window,
-                            EventName::VCSelect,
-                            json!({
-                                "in_vc": true
-                            }),
-                        );
-                        emit_event(
-                            &window,
-                            EventName::VCInfo,
-                            json!({
-                                "name": payload["data"]["name"],
-                                "users": payload["data"]["voice_states"]
-                            }),
-                        );
-
-                        if let Err(err) = receive_client
-                            .set_vc_events(current_state.channel_id, true)
-                            .await
-                        {
-                            emit_event(
-                                &window,
-                                EventName::Error,
-                                IpcError {
-                                    error_type: err.error_type,
-                                    message: err.message,
-                                    payload: err.payload,
-                                },
-                            );
-                        }
-                    }
-                }
-            } else {
-                if payload["evt"] == "ERROR" {
-                    // error occurred
-                    if payload["cmd"] == "AUTHORIZE" {
-                        // authorization error (user pressed cancel button)
-                        emit_event(
-                            &window,
-                            EventName::CriticalError,
-                            IpcError {
-                                error_type: IpcErrorType::Authorize,
-                                message: "User cancelled the app authorization.".to_string(),
-                                payload: None,
-                            },
-                        );
-                        break;
-                    }
-                    if payload["cmd"] == "SUBSCRIBE" {
-                        // client failed to subscribe events
-                        emit_event(
-                            &window,
-                            EventName::Error,
-                            IpcError {
-                                error_type: IpcErrorType::Subscribe,
-                                message: "Failed to subscribe to event.".to_string(),
-                                payload: None, // TODO
-                            },
-                        );
-                        continue;
-                    }
-                } else if payload["cmd"] == "DISPATCH" {
-                    if payload["evt"] == "VOICE_SETTINGS_UPDATE" {
-                        // vc settings update event
-                        emit_event(
-                            &window,
-                            EventName::VCMuteUpdate,
-                            json!({
-                                "mute": payload["data"]["mute"],
-                                "deaf": payload["data"]["deaf"],
-                            }),
-                        );
-                    } else if payload["evt"] == "VOICE_CHANNEL_SELECT" {
-                        // vc select update event
-                        let channel_id = &payload["data"]["channel_id"];
-                        if channel_id.is_null() {
-                            // left vc
-                            current_state.channel_id = Value::Null;
-                            let vc_select_payload = json!({
-                                "in_vc": false
-                            });
-                            emit_event(&window, EventName::VCSelect, vc_select_payload);
-                            if current_state.channel_id.is_null() {
-                                // unsubscribe events
-                                if let Err(err) = receive_client
-                                    .set_vc_events(current_state.channel_id, false)
-                                    .await
-                                {
-                                    emit_event(
-                                        &window,
-                                        EventName::Error,
-                                        IpcError {
-                                            error_type: err.error_type,
-                                            message: err.message,
-                                            payload: err.payload,
-                                        },
-                                    );
-                                }
-                            }
-                        } else {
-                            // joined vc
-                            current_state.channel_id = json!(channel_id);
-                            let vc_select_payload = json!({
-                                "in_vc": true,
-                            });
-                            emit_event(&window, EventName::VCSelect, vc_select_payload);
-
-                            // send current vc status command
-                            if let Err(err) = receive_client
-                                .send(json!({
-                                    "nonce": Uuid::new_v4().to_string(),
-                                    "cmd": "GET_SELECTED_VOICE_CHANNEL"
-                                }))
-                                .await
-                            {
-                                emit_event(&window, EventName::Error, err);
-                                continue;
-                            }
-
-                            /*
-                            // subscribe events for current channel
-                            if let Err(err) = receive_client
-                                .set_vc_events(current_state.channel_id, true)
-                                .await
-                            {
-                                emit_event(&window, EventName::Error, err);
-                            }
-                             */
-                        }
-                    } else if payload["evt"] == "VOICE_STATE_CREATE" {
-                        // someone joined vc
-                        if payload["data"]["user"]["id"].to_string()
-                            != current_state.user_id.to_string()
-                        {
-                            emit_event(
-                                &window,
-                                EventName::VCUser,
-                                json!({
-                                    "event": "JOIN",
-                                    "data": {
-                                        "id": payload["data"]["user"]["id"],
-                                        "username": payload["data"]["user"]["username"],
-                                        "avatar": payload["data"]["user"]["avatar"],
-                                        "nick": payload["data"]["nick"],
-                                        "mute": payload["data"]["voice_state"]["mute"],
-                                        "self_mute": payload["data"]["voice_state"]["self_mute"],
-                                        "deaf": payload["data"]["voice_state"]["deaf"],
-                                        "self_deaf": payload["data"]["voice_state"]["self_deaf"],
-                                    }
-                                }),
-                            );
-                        }
-                    } else if payload["evt"] == "VOICE_STATE_UPDATE" {
-                        if payload["data"]["user"]["id"].to_string()
-                            != current_state.user_id.to_string()
-                        {
-                            emit_event(
-                                &window,
-                                EventName::VCUser,
-                                json!({
-                                    "event": "UPDATE",
-                                    "data": {
-                                        "id": payload["data"]["user"]["id"],
-                                        "username": payload["data"]["user"]["username"],
-                                        "avatar": payload["data"]["user"]["avatar"],
-                                        "nick": payload["data"]["nick"],
-                                        "mute": payload["data"]["voice_state"]["mute"],
-                                        "self_mute": payload["data"]["voice_state"]["self_mute"],
-                                        "deaf": payload["data"]["voice_state"]["deaf"],
-                                        "self_deaf": payload["data"]["voice_state"]["self_deaf"],
-                                    }
-                                }),
-                            );
-                        }
-                    } else if payload["evt"] == "VOICE_STATE_DELETE" {
-                        if payload["data"]["user"]["id"].to_string()
-                            != current_state.user_id.to_string()
-                        {
-                            emit_event(
-                                &window,
-                                EventName::VCUser,
-                                json!({
-                                    "event": "LEAVE",
-                                    "data": {
-                                        "id": payload["data"]["user"]["id"]
-                                    }
-                                }),
-                            );
-                        }
-                    } else if payload["evt"] == "SPEAKING_START" {
-                        if payload["data"]["user_id"].to_string()
-                            == current_state.user_id.to_string()
-                        {
-                            emit_event(
-                                &window,
-                                EventName::VCSpeak,
-                                json!({
-                                    "user_id": payload["data"]["user_id"],
-                                    "is_me": true,
-                                    "speaking": true
-                                }),
-                            );
-                        } else {
-                            emit_event(
-                                &window,
-                                EventName::VCSpeak,
-                                json!({
-                                    "user_id": payload["data"]["user_id"],
-                                    "is_me": false,
-                                    "speaking": true
-                                }),
-                            );
-                        }
-                    } else if payload["evt"] == "SPEAKING_STOP" {
-                        if payload["data"]["user_id"].to_string()
-                            == current_state.user_id.to_string()
-                        {
-                            emit_event(
-                                &window,
-                                EventName::VCSpeak,
-                                json!({
-                                    "user_id": payload["data"]["user_id"],
-                                    "is_me": true,
-                                    "speaking": false
-                                }),
-                            );
-                        } else {
-                            emit_event(
-                                &window,
-                                EventName::VCSpeak,
-                                json!({
-                                    "user_id": payload["data"]["user_id"],
-                                    "is_me": false,
-                                    "speaking": false
-                                }),
-                            );
-                        }
-                    }
-                }
-            }
-        }
-    });
+    let instance = instance.map(PathBuf::from);
+    session::connect(window, Arc::clone(&client_manager), reauth, instance).await
+}
 
-    Ok(())
+#[tauri::command]
+async fn list_discord_instances() -> Result<Vec<DiscordInstance>, IpcError> {
+    // every socket is connected to once, which blocks
+    match spawn_blocking(discovery::list_discord_instances).await {
+        Ok(instances) => Ok(instances),
+        Err(err) => Err(IpcError {
+            error_type: IpcErrorType::Connect,
+            message: format!("Failed to look for discord instances.\n{}", err),
+            payload: None,
+        }),
+    }
 }
 
 #[tauri::command]
 async fn disconnect_ipc(
-    client_manager: State<'_, Arc<Mutex<SendIPCClient>>>,
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
 ) -> Result<(), IpcError> {
     let client = Arc::clone(&client_manager);
-    if let Err(err) = client.lock().await.ipc_client.close() {
-        return Err(IpcError {
-            error_type: IpcErrorType::Connect,
-            message: format!("Failed to close ipc socket.\n{}", err.to_string()),
-            payload: None,
-        });
+    let mut guard = client.lock().await;
+    guard.close();
+    guard.connection_state().disconnect(&window);
+    Ok(())
+}
+
+/// Disconnects and forgets the account, the next `connect_ipc` has to authorize again.
+#[tauri::command]
+async fn logout(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<(), AuthError> {
+    let client = Arc::clone(&client_manager);
+    let logout = {
+        let mut guard = client.lock().await;
+        let logout = guard.logout();
+        guard.connection_state().disconnect(&window);
+        logout
+    };
+    // revoking can take a while, the client is usable meanwhile
+    let result = logout.revoke().await;
+    window.emit_event(EventName::LoggedOut, ());
+    if let Err(err) = &result {
+        log_error("logout".to_string(), err.message.clone());
     }
+    result
+}
+
+/// The profiles and which of them `connect_ipc` uses.
+#[tauri::command]
+async fn list_profiles() -> Result<Value, AuthError> {
+    let config = get_config().map_err(|err| AuthError {
+        error_type: AuthErrorType::ConfigRead,
+        message: err.to_string(),
+    })?;
+    Ok(json!({
+        "active": config.profile,
+        "profiles": config.profiles
+    }))
+}
+
+#[tauri::command]
+async fn add_profile(name: String) -> Result<(), AuthError> {
+    update_config(|config| config.add_profile(&name))
+}
+
+/// Disconnects the current profile. Connect again with `connect_ipc` to use the new one.
+#[tauri::command]
+async fn switch_profile(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    name: String,
+) -> Result<(), AuthError> {
+    update_config(|config| config.select_profile(&name))?;
+    let client = Arc::clone(&client_manager);
+    let mut guard = client.lock().await;
+    guard.close();
+    guard.set_profile(name);
+    guard.connection_state().disconnect(&window);
     Ok(())
 }
 
+/// Forgets the profile and its refresh token, without revoking it.
+#[tauri::command]
+async fn remove_profile(name: String) -> Result<(), AuthError> {
+    update_config(|config| config.remove_profile(&name))?;
+    delete_tokens(&name).map_err(|err| AuthError {
+        error_type: AuthErrorType::ConfigSave,
+        message: format!("Could not delete the refresh token.\n{}", err),
+    })
+}
+
+#[tauri::command]
+async fn get_connection_state(
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<ConnectionState, IpcError> {
+    Ok(client_manager.lock().await.connection_state().get())
+}
+
 #[tauri::command]
 async fn disconnect_vc(
-    client_manager: State<'_, Arc<Mutex<SendIPCClient>>>,
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
 ) -> Result<(), IpcError> {
-    let client = Arc::clone(&client_manager);
-    let payload = json!({
-        "nonce": Uuid::new_v4().to_string(),
-        "cmd": "SELECT_VOICE_CHANNEL",
-        "args": {
-            "channel_id": Value::Null
-        }
+    let connection = connection_with(&window, &client_manager, &scope::VOICE_SCOPES).await?;
+    let command = Command::SelectVoiceChannel(SelectVoiceChannelArgs {
+        channel_id: None,
+        force: None,
     });
-    if let Err(err) = client.lock().await.send(payload.clone()).await {
-        return Err(err);
-    }
+    connection.send(command).await?;
     Ok(())
 }
 
 #[tauri::command]
-async fn toggle_mute(client_manager: State<'_, Arc<Mutex<SendIPCClient>>>) -> Result<(), IpcError> {
-    let client = Arc::clone(&client_manager);
-    let get_payload = json!({
-        "nonce": Uuid::new_v4().to_string(),
-        "cmd": "GET_VOICE_SETTINGS"
-    });
-    let response = match client.lock().await.send(get_payload).await {
-        Ok(r) => r,
-        Err(err) => {
-            return Err(err);
-        }
+async fn list_guilds(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<Vec<Guild>, IpcError> {
+    let connection = connection_with(&window, &client_manager, &[scope::GUILDS]).await?;
+    let response = connection.send(Command::GetGuilds).await?;
+    let Response::GetGuilds(guilds) = response else {
+        return Err(IpcError::unexpected_response(response));
     };
-    let mute = response["data"]["mute"].as_bool().unwrap();
-    let set_payload = json!({
-        "nonce": Uuid::new_v4().to_string(),
-        "cmd": "SET_VOICE_SETTINGS",
-        "args": {
-            "mute": !mute
-        }
-    });
-    if let Err(err) = client.lock().await.send(set_payload).await {
-        return Err(err);
+    Ok(guilds)
+}
+
+/// The voice and stage channels of the guild, the ones that can be joined.
+#[tauri::command]
+async fn list_channels(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    guild_id: String,
+) -> Result<Vec<GuildChannel>, IpcError> {
+    let connection = connection_with(&window, &client_manager, &[scope::GUILDS]).await?;
+    let response = connection
+        .send(Command::GetChannels(GetChannelsArgs { guild_id }))
+        .await?;
+    let Response::GetChannels(channels) = response else {
+        return Err(IpcError::unexpected_response(response));
     };
-    Ok(())
+    Ok(channels
+        .into_iter()
+        .filter(GuildChannel::is_voice)
+        .collect())
 }
 
+/// Discord refuses to move us out of another voice channel unless `force` is set.
+/// The session follows the channel from the VOICE_CHANNEL_SELECT that comes after.
 #[tauri::command]
-async fn toggle_deafen(
-    client_manager: State<'_, Arc<Mutex<SendIPCClient>>>,
-) -> Result<(), IpcError> {
-    let client = Arc::clone(&client_manager);
-    let get_payload = json!({
-        "nonce": Uuid::new_v4().to_string(),
-        "cmd": "GET_VOICE_SETTINGS"
+async fn join_voice_channel(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    channel_id: String,
+    force: Option<bool>,
+) -> Result<Option<Channel>, IpcError> {
+    let connection = connection_with(&window, &client_manager, &scope::VOICE_SCOPES).await?;
+    let command = Command::SelectVoiceChannel(SelectVoiceChannelArgs {
+        channel_id: Some(channel_id),
+        force,
     });
-    let response = match client.lock().await.send(get_payload).await {
-        Ok(r) => r,
-        Err(err) => {
-            return Err(err);
-        }
+    let response = connection.send(command).await?;
+    let Response::SelectVoiceChannel(channel) = response else {
+        return Err(IpcError::unexpected_response(response));
     };
-    let deaf = response["data"]["deaf"].as_bool().unwrap();
-    let set_payload = json!({
-        "nonce": Uuid::new_v4().to_string(),
-        "cmd": "SET_VOICE_SETTINGS",
-        "args": {
-            "deaf": !deaf
-        }
-    });
-    if let Err(err) = client.lock().await.send(set_payload).await {
-        return Err(err);
+    Ok(channel)
+}
+
+#[tauri::command]
+async fn list_favorite_channels() -> Result<Vec<FavoriteChannel>, AuthError> {
+    let config = get_config().map_err(|err| AuthError {
+        error_type: AuthErrorType::ConfigRead,
+        message: err.to_string(),
+    })?;
+    Ok(config.favorite_channels)
+}
+
+#[tauri::command]
+async fn add_favorite_channel(channel: FavoriteChannel) -> Result<(), AuthError> {
+    update_config(|config| {
+        config.add_favorite(channel);
+        Ok(())
+    })
+}
+
+#[tauri::command]
+async fn remove_favorite_channel(channel_id: String) -> Result<(), AuthError> {
+    update_config(|config| {
+        config.remove_favorite(&channel_id);
+        Ok(())
+    })
+}
+
+#[tauri::command]
+async fn get_voice_settings(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<VoiceSettings, IpcError> {
+    let vc_state = client_manager.lock().await.vc_state();
+    let connection = connection_with(&window, &client_manager, &scope::VOICE_SCOPES).await?;
+    connection.fetch_voice_settings(&vc_state).await
+}
+
+/// Changes only the given settings and returns all of them afterwards.
+#[tauri::command]
+async fn set_voice_settings(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    settings: SetVoiceSettingsArgs,
+) -> Result<VoiceSettings, IpcError> {
+    let vc_state = client_manager.lock().await.vc_state();
+    let connection = connection_with(&window, &client_manager, &scope::VOICE_SCOPES).await?;
+    connection.change_voice_settings(&vc_state, settings).await
+}
+
+/// How loud another member of our voice channel is for us.
+/// Read from the session's cache, which is filled from the channel's voice states
+/// when we join it and kept up to date by VOICE_STATE events, without asking discord.
+/// Anyone not in our voice channel is an `UnknownMember` error.
+#[tauri::command]
+async fn get_user_voice_settings(
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    user_id: String,
+) -> Result<UserVoiceSettings, IpcError> {
+    let vc_state = client_manager.lock().await.vc_state();
+    let vc = vc_state.lock().unwrap();
+    match vc.member(&user_id) {
+        Some(member) => Ok(member.voice_settings()),
+        None => Err(IpcError {
+            error_type: IpcErrorType::UnknownMember,
+            message: format!("{} is not in the voice channel.", user_id),
+            payload: None,
+        }),
+    }
+}
+
+/// Changes the volume, pan or local mute of another user and returns all of them afterwards.
+#[tauri::command]
+async fn set_user_voice_settings(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    settings: SetUserVoiceSettingsArgs,
+) -> Result<UserVoiceSettings, IpcError> {
+    let vc_state = client_manager.lock().await.vc_state();
+    let connection = connection_with(&window, &client_manager, &scope::VOICE_SCOPES).await?;
+    let response = connection
+        .send(Command::SetUserVoiceSettings(settings))
+        .await?;
+    let Response::SetUserVoiceSettings(settings) = response else {
+        return Err(IpcError::unexpected_response(response));
     };
-    Ok(())
+    vc_state.lock().unwrap().set_user_voice_settings(&settings);
+    Ok(settings)
 }
 
 #[tauri::command]
-async fn get_vc_info(
-    client_manager: State<'_, Arc<Mutex<SendIPCClient>>>,
-) -> Result<Value, IpcError> {
-    let client = Arc::clone(&client_manager);
-    let payload = json!({
-        "nonce": Uuid::new_v4().to_string(),
-        "cmd": "GET_SELECTED_VOICE_CHANNEL"
-    });
-    let response = match client.lock().await.send(payload).await {
-        Ok(r) => r,
-        Err(err) => {
-            return Err(err);
-        }
+async fn set_mute(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    mute: bool,
+) -> Result<VoiceSettings, IpcError> {
+    let args = SetVoiceSettingsArgs {
+        mute: Some(mute),
+        ..Default::default()
     };
-    if response["data"].is_null() {
+    set_voice_settings(window, client_manager, args).await
+}
+
+#[tauri::command]
+async fn set_deafen(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    deaf: bool,
+) -> Result<VoiceSettings, IpcError> {
+    let args = SetVoiceSettingsArgs {
+        deaf: Some(deaf),
+        ..Default::default()
+    };
+    set_voice_settings(window, client_manager, args).await
+}
+
+#[tauri::command]
+async fn toggle_mute(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<VoiceSettings, IpcError> {
+    let vc_state = client_manager.lock().await.vc_state();
+    let connection = connection_with(&window, &client_manager, &scope::VOICE_SCOPES).await?;
+    connection
+        .update_voice_settings(&vc_state, |settings| SetVoiceSettingsArgs {
+            mute: Some(!settings.mute),
+            ..Default::default()
+        })
+        .await
+}
+
+#[tauri::command]
+async fn toggle_deafen(
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<VoiceSettings, IpcError> {
+    let vc_state = client_manager.lock().await.vc_state();
+    let connection = connection_with(&window, &client_manager, &scope::VOICE_SCOPES).await?;
+    connection
+        .update_voice_settings(&vc_state, |settings| SetVoiceSettingsArgs {
+            deaf: Some(!settings.deaf),
+            ..Default::default()
+        })
+        .await
+}
+
+#[tauri::command]
+async fn get_vc_info(client_manager: State<'_, Arc<Mutex<IpcClient>>>) -> Result<Value, IpcError> {
+    let connection = connection(client_manager).await?;
+    let response = connection.send(Command::GetSelectedVoiceChannel).await?;
+    let Response::GetSelectedVoiceChannel(channel) = response else {
+        return Err(IpcError::unexpected_response(response));
+    };
+    match channel {
         // not currently in vc
-        Ok(json!({
+        None => Ok(json!({
             "in_vc": false
-        }))
-    } else {
+        })),
         // in vc
-        Ok(json!({
+        Some(channel) => Ok(json!({
             "in_vc": true,
-            "name": response["data"]["name"],
-            "users": response["data"]["voice_states"]
-        }))
+            "name": channel.name,
+            "users": channel.voice_states
+        })),
     }
 }
 
+/// The voice channel as the session knows it, without asking discord.
+#[tauri::command]
+async fn get_vc_state(
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+) -> Result<VoiceChannelState, IpcError> {
+    let vc_state = client_manager.lock().await.vc_state();
+    let state = vc_state.lock().unwrap().clone();
+    Ok(state)
+}
+
 #[tauri::command]
 async fn set_activity(
-    client_manager: State<'_, Arc<Mutex<SendIPCClient>>>,
-    mut activity: Value,
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
+    activity: Value,
 ) -> Result<(), IpcError> {
-    let client = Arc::clone(&client_manager);
-    let client_id = dotenv!("CLIENT_ID");
-    // activity["application_id"] = client_id.into();
-    let nonce = Uuid::new_v4().to_string();
-    let payload = json!({
-        "nonce": nonce.clone(),
-        "cmd": "SET_ACTIVITY",
-        "args": {
-            "pid": process::id(),
-            "activity": activity
-        }
+    let connection = connection_with(&window, &client_manager, &[scope::ACTIVITIES_WRITE]).await?;
+    let command = Command::SetActivity(SetActivityArgs {
+        pid: process::id(),
+        activity: Some(activity),
     });
-    let response = match client.lock().await.send(payload.clone()).await {
-        Ok(r) => r,
-        Err(err) => {
-            return Err(err);
-        }
-    };
-    println!("{}", response);
-    if response["evt"] == "ERROR" && response["nonce"] == nonce {
+    if let Err(err) = connection.send(command).await {
         return Err(IpcError {
-            error_type: IpcErrorType::EventSend,
-            message: format!("Failed to set activity.\n{}", response["data"]["message"]),
-            payload: Some(payload),
+            message: format!("Failed to set activity.\n{}", err.message),
+            ..err
         });
     }
     Ok(())
@@ -705,28 +433,18 @@ async fn set_activity(
 
 #[tauri::command]
 async fn clear_activity(
-    client_manager: State<'_, Arc<Mutex<SendIPCClient>>>,
+    window: Window,
+    client_manager: State<'_, Arc<Mutex<IpcClient>>>,
 ) -> Result<(), IpcError> {
-    let client = Arc::clone(&client_manager);
-    let nonce = Uuid::new_v4().to_string();
-    let payload = json!({
-        "nonce": nonce.clone(),
-        "cmd": "SET_ACTIVITY",
-        "args": {
-            "pid": process::id(),
-        }
+    let connection = connection_with(&window, &client_manager, &[scope::ACTIVITIES_WRITE]).await?;
+    let command = Command::SetActivity(SetActivityArgs {
+        pid: process::id(),
+        activity: None,
     });
-    let response = match client.lock().await.send(payload.clone()).await {
-        Ok(r) => r,
-        Err(err) => {
-            return Err(err);
-        }
-    };
-    if response["evt"] == "ERROR" && response["nonce"] == nonce {
+    if let Err(err) = connection.send(command).await {
         return Err(IpcError {
-            error_type: IpcErrorType::EventSend,
-            message: format!("Failed to clear activity.\n{}", payload["data"]["message"]),
-            payload: Some(payload),
+            message: format!("Failed to clear activity.\n{}", err.message),
+            ..err
         });
     }
     Ok(())
@@ -738,22 +456,54 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             connect_ipc,
             disconnect_vc,
+            list_guilds,
+            list_channels,
+            join_voice_channel,
+            list_favorite_channels,
+            add_favorite_channel,
+            remove_favorite_channel,
             toggle_mute,
             toggle_deafen,
+            set_mute,
+            set_deafen,
+            get_voice_settings,
+            set_voice_settings,
+            get_user_voice_settings,
+            set_user_voice_settings,
             disconnect_ipc,
+            logout,
+            list_profiles,
+            add_profile,
+            switch_profile,
+            remove_profile,
+            get_connection_state,
             get_vc_info,
+            get_vc_state,
             set_activity,
-            clear_activity
+            clear_activity,
+            list_discord_instances
         ])
         .setup(|app| {
+            let config = match get_config() {
+                Ok(c) => c,
+                Err(err) => {
+                    log_error(
+                        "config".to_string(),
+                        format!("Could not read the config, using the defaults.\n{}", err),
+                    );
+                    Config::default().with_env_overrides()
+                }
+            };
+            secret::use_store(secret::from_config(&config));
             // create ipc client
-            let client_id = dotenv!("CLIENT_ID");
-            /*
-
-            */
-            let client = Arc::new(Mutex::from(SendIPCClient::new(
-                DiscordIpcClient::new(client_id).expect("Failed to create client"),
-            )));
+            let client = Arc::new(Mutex::from(
+                IpcClient::new()
+                    .with_request_timeout(config.request_timeout())
+                    .with_api_client(DiscordAPIClient::from_config(&config))
+                    .with_profile(config.profile.clone())
+                    .with_scopes(config.scopes.clone())
+                    .with_voice_thresholds(VoiceConnectionThresholds::from_config(&config)),
+            ));
             app.manage(client);
             Ok(())
         })
@@ -0,0 +1,479 @@
+//! Where refresh tokens are kept: the OS keyring, or an encrypted file next to
+//! the config where there is none, e.g. on headless linux without a Secret Service.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use keyring::Entry;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, log::log_error};
+
+const SERVICE: &str = "discord-vc-status";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+/// Read by the file store to derive its key. Without it the key is derived
+/// from the machine id, which keeps the tokens from being copied to another
+/// machine but not from someone who can read this one.
+pub const PASSPHRASE_VAR: &str = "DISCORD_VC_STATUS_PASSPHRASE";
+
+#[derive(Debug)]
+pub enum SecretError {
+    /// There is nothing to keep secrets in, e.g. no Secret Service is running.
+    Unavailable(String),
+    Failed(String),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SecretError::Unavailable(message) => {
+                write!(f, "No secret storage is available.\n{message}")
+            }
+            SecretError::Failed(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+pub trait SecretStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<String>, SecretError>;
+    fn set(&self, key: &str, secret: &str) -> Result<(), SecretError>;
+    /// Deleting a key that isn't there is not an error.
+    fn delete(&self, key: &str) -> Result<(), SecretError>;
+}
+
+/// Which store the config asks for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum SecretBackend {
+    /// The keyring, and the encrypted file when the keyring is unavailable.
+    #[default]
+    Auto,
+    Keyring,
+    File,
+}
+
+static STORE: OnceLock<Box<dyn SecretStore>> = OnceLock::new();
+
+/// Makes `store` the one every secret goes to, only the first call counts.
+pub fn use_store(store: Box<dyn SecretStore>) {
+    if STORE.set(store).is_err() {
+        log_error(
+            "secret".to_string(),
+            "The secret store was already chosen.".to_string(),
+        );
+    }
+}
+
+/// The store chosen with `use_store`, the keyring until then.
+pub fn store() -> &'static dyn SecretStore {
+    STORE.get_or_init(|| Box::new(KeyringStore)).as_ref()
+}
+
+pub fn from_config(config: &Config) -> Box<dyn SecretStore> {
+    let file = || {
+        let key = match dotenvy::var(PASSPHRASE_VAR) {
+            Ok(passphrase) => FileKey::Passphrase(passphrase),
+            Err(_) => FileKey::Machine,
+        };
+        match confy::get_configuration_file_path("discord-vc-status", "discord-vc-status") {
+            Ok(path) => Some(FileStore::new(path.with_file_name("secrets.json"), key)),
+            Err(err) => {
+                log_error(
+                    "secret".to_string(),
+                    format!("Could not find the config directory.\n{}", err),
+                );
+                None
+            }
+        }
+    };
+    match (config.secret_store, file()) {
+        (SecretBackend::Keyring, _) | (_, None) => Box::new(KeyringStore),
+        (SecretBackend::File, Some(file)) => Box::new(file),
+        (SecretBackend::Auto, Some(file)) => {
+            Box::new(FallbackStore::new(Box::new(KeyringStore), Box::new(file)))
+        }
+    }
+}
+
+/// The OS keyring, one entry per key.
+pub struct KeyringStore;
+
+impl KeyringStore {
+    fn entry(key: &str) -> Result<Entry, SecretError> {
+        Entry::new(SERVICE, key).map_err(keyring_error)
+    }
+}
+
+fn keyring_error(err: keyring::Error) -> SecretError {
+    match err {
+        keyring::Error::NoStorageAccess(_) | keyring::Error::PlatformFailure(_) => {
+            SecretError::Unavailable(err.to_string())
+        }
+        _ => SecretError::Failed(err.to_string()),
+    }
+}
+
+impl SecretStore for KeyringStore {
+    fn get(&self, key: &str) -> Result<Option<String>, SecretError> {
+        match Self::entry(key)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(keyring_error(err)),
+        }
+    }
+
+    fn set(&self, key: &str, secret: &str) -> Result<(), SecretError> {
+        Self::entry(key)?
+            .set_password(secret)
+            .map_err(keyring_error)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), SecretError> {
+        match Self::entry(key)?.delete_credential() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(keyring_error(err)),
+        }
+    }
+}
+
+/// Uses `fallback` while `primary` is unavailable. Secrets saved to the fallback
+/// are still found after `primary` comes back.
+pub struct FallbackStore {
+    primary: Box<dyn SecretStore>,
+    fallback: Box<dyn SecretStore>,
+}
+
+impl FallbackStore {
+    pub fn new(primary: Box<dyn SecretStore>, fallback: Box<dyn SecretStore>) -> Self {
+        Self { primary, fallback }
+    }
+}
+
+impl SecretStore for FallbackStore {
+    fn get(&self, key: &str) -> Result<Option<String>, SecretError> {
+        match self.primary.get(key) {
+            Ok(Some(secret)) => Ok(Some(secret)),
+            Ok(None) | Err(SecretError::Unavailable(_)) => self.fallback.get(key),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set(&self, key: &str, secret: &str) -> Result<(), SecretError> {
+        match self.primary.set(key, secret) {
+            // a stale copy in the fallback would outlive the new secret
+            Ok(_) => self.fallback.delete(key),
+            Err(SecretError::Unavailable(_)) => self.fallback.set(key, secret),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), SecretError> {
+        let primary = match self.primary.delete(key) {
+            Err(SecretError::Unavailable(_)) => Ok(()),
+            result => result,
+        };
+        self.fallback.delete(key)?;
+        primary
+    }
+}
+
+/// What the file store's key is derived from.
+pub enum FileKey {
+    Passphrase(String),
+    Machine,
+}
+
+impl FileKey {
+    fn material(&self) -> Result<String, SecretError> {
+        match self {
+            FileKey::Passphrase(passphrase) => Ok(passphrase.clone()),
+            FileKey::Machine => machine_id(),
+        }
+    }
+}
+
+fn machine_id() -> Result<String, SecretError> {
+    let candidates = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+    for path in candidates {
+        if let Ok(id) = fs::read_to_string(path) {
+            if !id.trim().is_empty() {
+                return Ok(id.trim().to_string());
+            }
+        }
+    }
+    // windows
+    if let Ok(name) = std::env::var("COMPUTERNAME") {
+        return Ok(name);
+    }
+    Err(SecretError::Unavailable(format!(
+        "Could not find a machine id, set {PASSPHRASE_VAR} instead."
+    )))
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SecretFile {
+    /// For the key derivation, made when the file is first written.
+    salt: String,
+    /// Base64 of the nonce followed by the sealed secret.
+    secrets: HashMap<String, String>,
+}
+
+/// Secrets sealed with ChaCha20-Poly1305 in a JSON file. The key is derived
+/// with PBKDF2 from a passphrase or the machine id and the file's salt.
+pub struct FileStore {
+    path: PathBuf,
+    key: FileKey,
+    // read, change, write
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf, key: FileKey) -> Self {
+        Self {
+            path,
+            key,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> Result<Option<SecretFile>, SecretError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|err| failed("Could not read the secret file", err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(failed("Could not read the secret file", err)),
+        }
+    }
+
+    fn write(&self, file: &SecretFile) -> Result<(), SecretError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|err| failed("Could not write the secret file", err))?;
+        }
+        let contents = serde_json::to_string_pretty(file)
+            .map_err(|err| failed("Could not write the secret file", err))?;
+        // written next to it and renamed over it, so the file is never half
+        // written and never readable by others, not even for a moment
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temp = self.path.with_file_name(name);
+        let written =
+            write_private(&temp, contents.as_bytes()).and_then(|_| fs::rename(&temp, &self.path));
+        if let Err(err) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(failed("Could not write the secret file", err));
+        }
+        Ok(())
+    }
+
+    fn key(&self, salt: &str) -> Result<LessSafeKey, SecretError> {
+        let salt = STANDARD
+            .decode(salt)
+            .map_err(|err| failed("The secret file is damaged", err))?;
+        let mut key = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            &salt,
+            self.key.material()?.as_bytes(),
+            &mut key,
+        );
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+            .map_err(|_| SecretError::Failed("Could not make the file key.".to_string()))?;
+        Ok(LessSafeKey::new(key))
+    }
+}
+
+/// Writes a new file only the user can read and waits until it is on disk.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // a leftover from a failed write may have other permissions
+    let _ = fs::remove_file(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+fn failed(message: &str, err: impl fmt::Display) -> SecretError {
+    SecretError::Failed(format!("{message}.\n{err}"))
+}
+
+fn random<const N: usize>() -> Result<[u8; N], SecretError> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| SecretError::Failed("The random number generator failed.".to_string()))?;
+    Ok(bytes)
+}
+
+impl SecretStore for FileStore {
+    fn get(&self, key: &str) -> Result<Option<String>, SecretError> {
+        let _lock = self.lock.lock().unwrap();
+        let Some(file) = self.read()? else {
+            return Ok(None);
+        };
+        let Some(sealed) = file.secrets.get(key) else {
+            return Ok(None);
+        };
+        let mut sealed = STANDARD
+            .decode(sealed)
+            .map_err(|err| failed("The secret file is damaged", err))?;
+        if sealed.len() < NONCE_LEN {
+            return Err(SecretError::Failed(
+                "The secret file is damaged.".to_string(),
+            ));
+        }
+        let mut in_out = sealed.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed)
+            .map_err(|_| SecretError::Failed("The secret file is damaged.".to_string()))?;
+        // the key name is authenticated too, so secrets can't be swapped around
+        let secret = self
+            .key(&file.salt)?
+            .open_in_place(nonce, Aad::from(key.as_bytes()), &mut in_out)
+            .map_err(|_| {
+                SecretError::Failed(
+                    "Could not decrypt the secret file, the passphrase or machine changed."
+                        .to_string(),
+                )
+            })?;
+        String::from_utf8(secret.to_vec())
+            .map(Some)
+            .map_err(|err| failed("The secret file is damaged", err))
+    }
+
+    fn set(&self, key: &str, secret: &str) -> Result<(), SecretError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut file = match self.read()? {
+            Some(f) => f,
+            None => SecretFile {
+                salt: STANDARD.encode(random::<SALT_LEN>()?),
+                secrets: HashMap::new(),
+            },
+        };
+        let nonce = random::<NONCE_LEN>()?;
+        let mut sealed = secret.as_bytes().to_vec();
+        self.key(&file.salt)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| SecretError::Failed("Could not encrypt the secret.".to_string()))?;
+        let mut stored = nonce.to_vec();
+        stored.append(&mut sealed);
+        file.secrets
+            .insert(key.to_string(), STANDARD.encode(stored));
+        self.write(&file)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), SecretError> {
+        let _lock = self.lock.lock().unwrap();
+        let Some(mut file) = self.read()? else {
+            return Ok(());
+        };
+        if file.secrets.remove(key).is_some() {
+            self.write(&file)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    fn temp_file() -> PathBuf {
+        env::temp_dir()
+            .join(format!("discord-vc-status-{}", Uuid::new_v4()))
+            .join("secrets.json")
+    }
+
+    fn passphrase(p: &str) -> FileKey {
+        FileKey::Passphrase(p.to_string())
+    }
+
+    #[test]
+    fn file_store_keeps_secrets_encrypted() {
+        let path = temp_file();
+        let store = FileStore::new(path.clone(), passphrase("hunter2"));
+        assert!(matches!(store.get("refresh_token"), Ok(None)));
+
+        store.set("refresh_token", "the token").unwrap();
+        assert_eq!(
+            store.get("refresh_token").unwrap().as_deref(),
+            Some("the token")
+        );
+        assert!(!fs::read_to_string(&path).unwrap().contains("the token"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let reopened = FileStore::new(path.clone(), passphrase("hunter2"));
+        assert_eq!(
+            reopened.get("refresh_token").unwrap().as_deref(),
+            Some("the token")
+        );
+        let wrong = FileStore::new(path.clone(), passphrase("hunter3"));
+        assert!(wrong.get("refresh_token").is_err());
+
+        store.delete("refresh_token").unwrap();
+        assert!(matches!(store.get("refresh_token"), Ok(None)));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    /// A keyring on a machine without a Secret Service.
+    struct NoKeyring;
+
+    impl SecretStore for NoKeyring {
+        fn get(&self, _: &str) -> Result<Option<String>, SecretError> {
+            Err(SecretError::Unavailable("no Secret Service".to_string()))
+        }
+        fn set(&self, _: &str, _: &str) -> Result<(), SecretError> {
+            Err(SecretError::Unavailable("no Secret Service".to_string()))
+        }
+        fn delete(&self, _: &str) -> Result<(), SecretError> {
+            Err(SecretError::Unavailable("no Secret Service".to_string()))
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_file_without_a_keyring() {
+        let path = temp_file();
+        let file = FileStore::new(path.clone(), passphrase("hunter2"));
+        let store = FallbackStore::new(Box::new(NoKeyring), Box::new(file));
+
+        store.set("refresh_token", "the token").unwrap();
+        assert_eq!(
+            store.get("refresh_token").unwrap().as_deref(),
+            Some("the token")
+        );
+        store.delete("refresh_token").unwrap();
+        assert!(matches!(store.get("refresh_token"), Ok(None)));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
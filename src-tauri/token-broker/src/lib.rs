@@ -0,0 +1,329 @@
+//! A reference token broker for discord-vc-status.
+//!
+//! The app POSTs one of
+//!
+//! ```json
+//! { "grant_type": "authorization_code", "code": "...", "redirect_uri": "...", "code_verifier": "..." }
+//! { "grant_type": "refresh_token", "refresh_token": "..." }
+//! ```
+//!
+//! to `/token`, `code_verifier` only when the app authorized with PKCE. The broker adds the client credentials, forwards it to discord's
+//! token endpoint and answers with discord's status and body as they are, along with
+//! its `Retry-After` and `X-RateLimit-*` headers so the app can honor the rate limits.
+//!
+//! Logging out POSTs `{ "token": "..." }` with the refresh token to `/token/revoke`,
+//! which is forwarded to discord's revocation endpoint the same way.
+
+pub mod http;
+
+use std::{io, sync::Arc};
+
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+
+pub const DISCORD_TOKEN_URL: &str = "https://discord.com/api/oauth2/token";
+
+pub struct BrokerConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the requests are forwarded to, discord's token endpoint outside of tests.
+    /// Revocations go to `<token_url>/revoke`.
+    pub token_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+enum TokenRequest {
+    AuthorizationCode {
+        code: String,
+        redirect_uri: String,
+        // when the app authorized with PKCE
+        code_verifier: Option<String>,
+    },
+    RefreshToken {
+        refresh_token: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct RevokeRequest {
+    token: String,
+}
+
+impl RevokeRequest {
+    fn form(&self) -> Vec<(&str, &str)> {
+        vec![
+            ("token", self.token.as_str()),
+            ("token_type_hint", "refresh_token"),
+        ]
+    }
+}
+
+impl TokenRequest {
+    fn form(&self) -> Vec<(&str, &str)> {
+        match self {
+            TokenRequest::AuthorizationCode {
+                code,
+                redirect_uri,
+                code_verifier,
+            } => {
+                let mut form = vec![
+                    ("grant_type", "authorization_code"),
+                    ("code", code.as_str()),
+                    ("redirect_uri", redirect_uri.as_str()),
+                ];
+                if let Some(verifier) = code_verifier {
+                    form.push(("code_verifier", verifier));
+                }
+                form
+            }
+            TokenRequest::RefreshToken { refresh_token } => vec![
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ],
+        }
+    }
+}
+
+/// Answers token requests on `listener` until accepting fails.
+pub async fn serve(listener: TcpListener, config: BrokerConfig) -> io::Result<()> {
+    let config = Arc::new(config);
+    let client = Client::new();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let config = Arc::clone(&config);
+        let client = client.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &client, &config).await {
+                eprintln!("Failed to answer a request.\n{err}");
+            }
+        });
+    }
+}
+
+/// What is answered: the status, headers passed on from discord and the body.
+type Answer = (u16, Vec<(String, String)>, String);
+
+async fn handle(mut stream: TcpStream, client: &Client, config: &BrokerConfig) -> io::Result<()> {
+    let (status, passed_on, body) = match http::read_request(&mut stream).await {
+        Ok(request) if request.path != "/token" && request.path != "/token/revoke" => {
+            error(404, "not_found", "Unknown path.")
+        }
+        Ok(request) if request.method != "POST" => {
+            error(405, "invalid_request", "Only POST is supported.")
+        }
+        Ok(request) if request.path == "/token/revoke" => {
+            match serde_json::from_slice::<RevokeRequest>(&request.body) {
+                Ok(revoke) => {
+                    let url = format!("{}/revoke", config.token_url);
+                    forward(client, config, &url, &revoke.form()).await
+                }
+                Err(err) => error(400, "invalid_request", &err.to_string()),
+            }
+        }
+        Ok(request) => match serde_json::from_slice::<TokenRequest>(&request.body) {
+            Ok(token_request) => {
+                forward(client, config, &config.token_url, &token_request.form()).await
+            }
+            Err(err) => error(400, "invalid_request", &err.to_string()),
+        },
+        Err(err) => error(400, "invalid_request", &err.to_string()),
+    };
+    let mut headers = vec![("Content-Type", "application/json")];
+    headers.extend(passed_on.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+    http::write_response(&mut stream, status, &headers, body.as_bytes()).await
+}
+
+fn is_rate_limit_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case("retry-after")
+        || name.to_ascii_lowercase().starts_with("x-ratelimit-")
+}
+
+async fn forward(
+    client: &Client,
+    config: &BrokerConfig,
+    url: &str,
+    form: &[(&str, &str)],
+) -> Answer {
+    let response = client
+        .post(url)
+        .basic_auth(&config.client_id, Some(&config.client_secret))
+        .form(form)
+        .send()
+        .await;
+    let response = match response {
+        Ok(r) => r,
+        Err(err) => return error(502, "bad_gateway", &err.to_string()),
+    };
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| is_rate_limit_header(name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    match response.text().await {
+        Ok(body) => (status, headers, body),
+        Err(err) => error(502, "bad_gateway", &err.to_string()),
+    }
+}
+
+/// An error in the shape of discord's OAuth2 errors, so the app reads both the same way.
+fn error(status: u16, error: &str, description: &str) -> Answer {
+    let body = json!({ "error": error, "error_description": description });
+    (status, Vec::new(), body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Stands in for discord's token endpoint and keeps the last request.
+    async fn upstream() -> (String, Arc<Mutex<Option<http::Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/oauth2/token", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(None));
+        let last = Arc::clone(&received);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = http::read_request(&mut stream).await.unwrap();
+                *last.lock().unwrap() = Some(request);
+                let body = json!({
+                    "access_token": "access",
+                    "token_type": "Bearer",
+                    "expires_in": 604800,
+                    "refresh_token": "refresh",
+                    "scope": "rpc identify"
+                });
+                let body = body.to_string();
+                http::write_response(&mut stream, 200, &[], body.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    async fn broker(token_url: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let config = BrokerConfig {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            token_url,
+        };
+        tokio::spawn(serve(listener, config));
+        url
+    }
+
+    #[tokio::test]
+    async fn forwards_codes_with_the_client_credentials() {
+        let (token_url, received) = upstream().await;
+        let url = broker(token_url).await;
+
+        let response = Client::new()
+            .post(&url)
+            .json(&json!({
+                "grant_type": "authorization_code",
+                "code": "the code",
+                "redirect_uri": "http://localhost"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let tokens: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(tokens["access_token"], "access");
+
+        let request = received.lock().unwrap().take().unwrap();
+        assert_eq!(request.path, "/api/oauth2/token");
+        // "client:secret"
+        assert_eq!(
+            request.header("authorization"),
+            Some("Basic Y2xpZW50OnNlY3JldA==")
+        );
+        assert_eq!(
+            String::from_utf8(request.body).unwrap(),
+            "grant_type=authorization_code&code=the+code&redirect_uri=http%3A%2F%2Flocalhost"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_revocations() {
+        let (token_url, received) = upstream().await;
+        let url = broker(token_url).await;
+
+        let response = Client::new()
+            .post(format!("{url}/revoke"))
+            .json(&json!({ "token": "refresh" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let request = received.lock().unwrap().take().unwrap();
+        assert_eq!(request.path, "/api/oauth2/token/revoke");
+        assert_eq!(
+            request.header("authorization"),
+            Some("Basic Y2xpZW50OnNlY3JldA==")
+        );
+        assert_eq!(
+            String::from_utf8(request.body).unwrap(),
+            "token=refresh&token_type_hint=refresh_token"
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_rate_limits_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_url = format!("http://{}/api/oauth2/token", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            http::read_request(&mut stream).await.unwrap();
+            let headers = [
+                ("Retry-After", "2"),
+                ("X-RateLimit-Remaining", "0"),
+                ("X-RateLimit-Reset-After", "1.5"),
+                ("Set-Cookie", "__dcfduid=1"),
+            ];
+            let body = json!({ "message": "You are being rate limited.", "retry_after": 2.0 });
+            http::write_response(&mut stream, 429, &headers, body.to_string().as_bytes())
+                .await
+                .unwrap();
+        });
+        let url = broker(token_url).await;
+
+        let response = Client::new()
+            .post(&url)
+            .json(&json!({ "grant_type": "refresh_token", "refresh_token": "refresh" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 429);
+        let headers = response.headers();
+        assert_eq!(headers["retry-after"], "2");
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        assert_eq!(headers["x-ratelimit-reset-after"], "1.5");
+        assert!(headers.get("set-cookie").is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_grants() {
+        let (token_url, received) = upstream().await;
+        let url = broker(token_url).await;
+
+        let response = Client::new()
+            .post(&url)
+            .json(&json!({ "grant_type": "client_credentials" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        let error: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(error["error"], "invalid_request");
+        assert!(received.lock().unwrap().is_none());
+    }
+}
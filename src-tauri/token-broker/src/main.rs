@@ -0,0 +1,46 @@
+//! Runs the token broker.
+//!
+//! CLIENT_ID=... CLIENT_SECRET=... token-broker [address]
+//!
+//! The address defaults to 127.0.0.1:8787. Point the app's `token_broker_url`
+//! config at `http://<address>/token`.
+
+use std::{env, process};
+
+use token_broker::{serve, BrokerConfig, DISCORD_TOKEN_URL};
+use tokio::net::TcpListener;
+
+fn required(name: &str) -> String {
+    match env::var(name) {
+        Ok(v) => v,
+        Err(_) => {
+            eprintln!("{name} is not set.");
+            process::exit(1);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let address = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8787".to_string());
+    let config = BrokerConfig {
+        client_id: required("CLIENT_ID"),
+        client_secret: required("CLIENT_SECRET"),
+        token_url: env::var("DISCORD_TOKEN_URL").unwrap_or_else(|_| DISCORD_TOKEN_URL.to_string()),
+    };
+
+    let listener = match TcpListener::bind(&address).await {
+        Ok(l) => l,
+        Err(err) => {
+            eprintln!("Failed to listen on {address}.\n{err}");
+            process::exit(1);
+        }
+    };
+    println!("Listening on http://{address}/token");
+    if let Err(err) = serve(listener, config).await {
+        eprintln!("The broker stopped.\n{err}");
+        process::exit(1);
+    }
+}
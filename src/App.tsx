@@ -1,14 +1,25 @@
 import { useEffect, useRef, useState } from 'react';
 import { invoke } from '@tauri-apps/api/tauri';
 import { UnlistenFn, listen } from '@tauri-apps/api/event';
-import { IpcError, RustError } from './utils/error';
+import { IpcError, RustError, isFatalClose, missingScope } from './utils/error';
 import { UserData } from './types/user';
-import { VCSelectPayload, VCMuteUpdatePayload, VCInfoPayload, VCUserPayload, VCSpeakPayload } from './types/event';
+import {
+  VCSelectPayload,
+  VCMuteUpdatePayload,
+  VCInfoPayload,
+  VCUserPayload,
+  VCSpeakPayload,
+  ReconnectingPayload,
+  VCConnectionPayload,
+  VCConnectionWarningPayload,
+} from './types/event';
 import { formatUserData } from './utils/vc';
 import VCSettings from './components/VCSettings';
-import { Box, Grid } from '@mui/material';
+import { Box, Button, Grid } from '@mui/material';
 import UserList from './components/UserList';
 import SetActivity from './components/SetActivity';
+import Profiles from './components/Profiles';
+import Channels from './components/Channels';
 import { message } from '@tauri-apps/api/dialog';
 import { exit } from '@tauri-apps/api/process';
 
@@ -20,7 +31,11 @@ function App() {
   const [isSpeaking, setIsSpeaking] = useState(false);
   const [userList, setUserList] = useState<UserData[]>([]);
   const [vcName, setVCName] = useState('');
+  const [vcConnection, setVCConnection] = useState<VCConnectionPayload | null>(null);
   const [userId, setUserId] = useState('');
+  const [loggedIn, setLoggedIn] = useState(true);
+  // false until the voice scopes are granted, the mute and deafen state isn't sent before
+  const [voiceSettingsFollowed, setVoiceSettingsFollowed] = useState(true);
 
   const userListRef = useRef<UserData[]>();
   userListRef.current = userList;
@@ -115,6 +130,16 @@ function App() {
       });
   };
 
+  const login = () => {
+    invoke('connect_ipc', { reauth: false })
+      .then(() => {
+        setLoggedIn(true);
+      })
+      .catch((e: IpcError) => {
+        console.error(e);
+      });
+  };
+
   useEffect(() => {
     const unlistenFuncs: UnlistenFn[] = [];
     const initIPC = async () => {
@@ -125,14 +150,15 @@ function App() {
           console.log('Connected to ipc.');
         })
         .catch((e: IpcError) => {
-          if (e.error_type === 'CreateClient') {
+          if (e.error_type === 'CreateClient' || isFatalClose(e)) {
             // kill the process cuz its un-recoverable
+            // (discord closes the handshake for e.g. an invalid client id)
             failed = true;
             message(e.message, 'Fatal Error').then(() => {
               exit(1);
             });
-          } else if (e.error_type === 'Connect') {
-            // discord client might be not running
+          } else if (e.error_type === 'Connect' || e.error_type === 'Closed') {
+            // discord client might be not running, or closed us for a passing reason
             // schedule the `connect_ipc` command in 10 secs or smth
             failed = true;
             setTimeout(() => {
@@ -173,6 +199,9 @@ function App() {
 
       const unlistenError = await listen<RustError>('error', (e) => {
         console.error(`Error: ${e.payload.error_type}\n  ${e.payload.message}`);
+        if (missingScope(e.payload) === 'rpc.voice.read') {
+          setVoiceSettingsFollowed(false);
+        }
       });
       unlistenFuncs.push(unlistenError);
 
@@ -192,6 +221,7 @@ function App() {
         const mute = e.payload.deaf ? true : e.payload.mute;
         setIsMute(mute);
         setIsDeafen(e.payload.deaf);
+        setVoiceSettingsFollowed(true);
       });
       unlistenFuncs.push(unlistenVCUpdate);
 
@@ -238,10 +268,48 @@ function App() {
       });
       unlistenFuncs.push(unlistenVcSpeak);
 
+      const unlistenVcConnection = await listen<VCConnectionPayload>('vc_connection', (e) => {
+        setVCConnection(e.payload);
+      });
+      unlistenFuncs.push(unlistenVcConnection);
+
+      const unlistenVcConnectionWarning = await listen<VCConnectionWarningPayload>('vc_connection_warning', (e) => {
+        console.warn(e.payload);
+      });
+      unlistenFuncs.push(unlistenVcConnectionWarning);
+
       const unlistenUserId = await listen<string>('user_id', (e) => {
         setUserId(e.payload);
       });
       unlistenFuncs.push(unlistenUserId);
+
+      const unlistenReconnecting = await listen<ReconnectingPayload>('reconnecting', (e) => {
+        console.log(`Lost connection to discord. Retrying in ${e.payload.delay_ms}ms (attempt ${e.payload.attempt})`);
+        // the vc state is sent again once the connection is back
+        setInVC(false);
+        setVCName('');
+        setUserList([]);
+        setIsSpeaking(false);
+      });
+      unlistenFuncs.push(unlistenReconnecting);
+
+      const unlistenReconnected = await listen('reconnected', () => {
+        console.log('Reconnected to discord');
+      });
+      unlistenFuncs.push(unlistenReconnected);
+
+      const unlistenLoggedOut = await listen('logged_out', () => {
+        console.log('Logged out');
+        setLoggedIn(false);
+        setInVC(false);
+        setIsMute(false);
+        setIsDeafen(false);
+        setIsSpeaking(false);
+        setVCName('');
+        setUserList([]);
+        setUserId('');
+      });
+      unlistenFuncs.push(unlistenLoggedOut);
     };
 
     initIPC();
@@ -259,10 +327,33 @@ function App() {
     console.log(userList);
   }, [userList]);
 
+  if (!loggedIn) {
+    return (
+      <Box
+        height={`${window.innerHeight}px`}
+        display={'flex'}
+        alignItems={'center'}
+        justifyContent={'center'}
+      >
+        <Button variant="contained" onClick={login}>
+          Log in with Discord
+        </Button>
+      </Box>
+    );
+  }
+
   return (
     <Box height={`${window.innerHeight}px`}>
       <Box height={'10%'}>
-        <VCSettings inVC={inVC} isMute={isMute} isDeafen={isDeafen} isSpeaking={isSpeaking} vcName={vcName} />
+        <VCSettings
+          inVC={inVC}
+          isMute={isMute}
+          isDeafen={isDeafen}
+          voiceSettingsFollowed={voiceSettingsFollowed}
+          isSpeaking={isSpeaking}
+          vcName={vcName}
+          vcConnection={inVC ? vcConnection : null}
+        />
       </Box>
       <Box>
         <Grid container>
@@ -271,6 +362,8 @@ function App() {
           </Grid>
           <Grid item xs={5}>
             <SetActivity />
+            <Profiles />
+            <Channels />
           </Grid>
         </Grid>
       </Box>
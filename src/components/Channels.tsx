@@ -0,0 +1,150 @@
+import { Button, FormControl, InputLabel, MenuItem, Select, Stack } from '@mui/material';
+import { useEffect, useRef, useState } from 'react';
+import { listen } from '@tauri-apps/api/event';
+import { invoke } from '@tauri-apps/api/tauri';
+import { FavoriteChannel, Guild, GuildChannel } from '../types/channel';
+import { RustError } from '../utils/error';
+
+const Channels = () => {
+  const [favorites, setFavorites] = useState<FavoriteChannel[]>([]);
+  const [guilds, setGuilds] = useState<Guild[]>([]);
+  const [guildId, setGuildId] = useState('');
+  const [channels, setChannels] = useState<GuildChannel[]>([]);
+  const [channelId, setChannelId] = useState('');
+  // listing them asks the user for the guilds scope, so only once they want it
+  const guildsWanted = useRef(false);
+
+  const refreshFavorites = () => {
+    invoke<FavoriteChannel[]>('list_favorite_channels')
+      .then((r) => {
+        setFavorites(r);
+      })
+      .catch((e: RustError) => {
+        console.error(e);
+      });
+  };
+
+  const refreshGuilds = () => {
+    invoke<Guild[]>('list_guilds')
+      .then((r) => {
+        setGuilds(r);
+      })
+      .catch((e: RustError) => {
+        console.error(e);
+      });
+  };
+
+  const selectGuild = (id: string) => {
+    setGuildId(id);
+    setChannelId('');
+    invoke<GuildChannel[]>('list_channels', { guildId: id })
+      .then((r) => {
+        setChannels(r);
+      })
+      .catch((e: RustError) => {
+        console.error(e);
+      });
+  };
+
+  const join = (id: string) => {
+    invoke('join_voice_channel', { channelId: id, force: true }).catch((e: RustError) => {
+      console.error(e);
+    });
+  };
+
+  const addFavorite = () => {
+    const channel = channels.find((c) => c.id === channelId);
+    if (!channel) return;
+    invoke('add_favorite_channel', { channel: { id: channel.id, name: channel.name, guild_id: guildId } })
+      .catch((e: RustError) => {
+        console.error(e);
+      })
+      .finally(refreshFavorites);
+  };
+
+  const removeFavorite = (id: string) => {
+    invoke('remove_favorite_channel', { channelId: id })
+      .catch((e: RustError) => {
+        console.error(e);
+      })
+      .finally(refreshFavorites);
+  };
+
+  useEffect(() => {
+    refreshFavorites();
+    // discord only lists the guilds once authenticated,
+    // user_id is sent then and again after every reconnect
+    const unlisten = listen<string>('user_id', () => {
+      if (guildsWanted.current) refreshGuilds();
+    });
+    return () => {
+      unlisten.then((f) => {
+        f();
+      });
+    };
+  }, []);
+
+  return (
+    <Stack spacing={2}>
+      {favorites.map((f) => (
+        <Stack direction="row" spacing={1} key={f.id}>
+          <Button variant="contained" onClick={() => join(f.id)}>
+            {f.name}
+          </Button>
+          <Button variant="text" onClick={() => removeFavorite(f.id)}>
+            Remove
+          </Button>
+        </Stack>
+      ))}
+      <FormControl>
+        <InputLabel id="guild-select">Server</InputLabel>
+        <Select
+          labelId="guild-select"
+          value={guildId}
+          label="Server"
+          onOpen={() => {
+            if (guildsWanted.current) return;
+            guildsWanted.current = true;
+            refreshGuilds();
+          }}
+          onChange={(e) => {
+            selectGuild(e.target.value);
+          }}
+        >
+          {guilds.map((g) => (
+            <MenuItem value={g.id} key={g.id}>
+              {g.name}
+            </MenuItem>
+          ))}
+        </Select>
+      </FormControl>
+      <FormControl>
+        <InputLabel id="channel-select">Channel</InputLabel>
+        <Select
+          labelId="channel-select"
+          value={channelId}
+          label="Channel"
+          onChange={(e) => {
+            setChannelId(e.target.value);
+          }}
+        >
+          {channels.map((c) => (
+            <MenuItem value={c.id} key={c.id}>
+              {c.name}
+            </MenuItem>
+          ))}
+        </Select>
+      </FormControl>
+      <Stack direction="row" spacing={1}>
+        <Button variant="outlined" disabled={!channelId} onClick={() => join(channelId)}>
+          Join
+        </Button>
+        <Button variant="outlined" disabled={!channelId} onClick={addFavorite}>
+          Favorite
+        </Button>
+      </Stack>
+    </Stack>
+  );
+};
+
+export default Channels;
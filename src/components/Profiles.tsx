@@ -0,0 +1,84 @@
+import { Button, FormControl, InputLabel, MenuItem, Select, Stack, TextField } from '@mui/material';
+import { useEffect, useState } from 'react';
+import { invoke } from '@tauri-apps/api/tauri';
+import { ProfileList } from '../types/profile';
+import { IpcError, RustError } from '../utils/error';
+
+const Profiles = () => {
+  const [profileList, setProfileList] = useState<ProfileList>({ active: '', profiles: [] });
+  const [newName, setNewName] = useState('');
+
+  const refresh = () => {
+    invoke<ProfileList>('list_profiles')
+      .then((r) => {
+        setProfileList(r);
+      })
+      .catch((e: RustError) => {
+        console.error(e);
+      });
+  };
+
+  const switchProfile = (name: string) => {
+    invoke('switch_profile', { name })
+      .then(() =>
+        invoke('connect_ipc', { reauth: true }).catch((e: IpcError) => {
+          if (e.error_type !== 'ReAuth') throw e;
+          // same fallback as on startup
+          console.error('Failed to reauth. Trying to do normal auth...');
+          return invoke('connect_ipc', { reauth: false });
+        }),
+      )
+      .catch((e: RustError) => {
+        console.error(e);
+      })
+      .finally(refresh);
+  };
+
+  const addProfile = () => {
+    invoke('add_profile', { name: newName })
+      .then(() => {
+        setNewName('');
+      })
+      .catch((e: RustError) => {
+        console.error(e);
+      })
+      .finally(refresh);
+  };
+
+  useEffect(refresh, []);
+
+  return (
+    <Stack spacing={2}>
+      <FormControl>
+        <InputLabel id="profile-select">Profile</InputLabel>
+        <Select
+          labelId="profile-select"
+          value={profileList.active}
+          label="Profile"
+          onChange={(e) => {
+            switchProfile(e.target.value);
+          }}
+        >
+          {profileList.profiles.map((p) => (
+            <MenuItem value={p.name} key={p.name}>
+              {p.user ? `${p.name} (${p.user.global_name ?? p.user.username})` : p.name}
+            </MenuItem>
+          ))}
+        </Select>
+      </FormControl>
+      <TextField
+        label="New profile"
+        variant="outlined"
+        value={newName}
+        onChange={(e) => {
+          setNewName(e.target.value);
+        }}
+      />
+      <Button variant="outlined" onClick={addProfile}>
+        Add
+      </Button>
+    </Stack>
+  );
+};
+
+export default Profiles;
@@ -1,22 +1,29 @@
 import { invoke } from '@tauri-apps/api/tauri';
-import { Grid, IconButton } from '@mui/material';
+import { Grid, IconButton, Typography } from '@mui/material';
 import SettingsPhoneIcon from '@mui/icons-material/SettingsPhone';
 import MicIcon from '@mui/icons-material/Mic';
 import MicOffIcon from '@mui/icons-material/MicOff';
 import HeadsetIcon from '@mui/icons-material/Headset';
 import HeadsetOffIcon from '@mui/icons-material/HeadsetOff';
 import CallEndIcon from '@mui/icons-material/CallEnd';
-import { IpcError } from '../utils/error';
+import LogoutIcon from '@mui/icons-material/Logout';
+import { IpcError, RustError } from '../utils/error';
+import { VCConnectionPayload } from '../types/event';
 
 type Props = {
   inVC: boolean;
   isMute: boolean;
   isDeafen: boolean;
+  voiceSettingsFollowed: boolean;
   isSpeaking: boolean;
   vcName: string;
+  vcConnection: VCConnectionPayload | null;
 };
 
-const VCSettings = ({ inVC, isMute, isDeafen, isSpeaking, vcName }: Props) => {
+const VCSettings = ({ inVC, isMute, isDeafen, voiceSettingsFollowed, isSpeaking, vcName, vcConnection }: Props) => {
+  // toggling asks for the voice scopes, the state is followed from then on
+  const voiceSettingsHint = voiceSettingsFollowed ? undefined : 'Click to let the app follow your mute and deafen state';
+
   const disconnectVC = async () => {
     invoke('disconnect_vc').catch((e: IpcError) => {
       // failed to send disconnect payload
@@ -36,6 +43,13 @@ const VCSettings = ({ inVC, isMute, isDeafen, isSpeaking, vcName }: Props) => {
     });
   };
 
+  const logout = async () => {
+    // the ui is reset by the `logged_out` event, which is sent even if revoking failed
+    invoke('logout').catch((e: RustError) => {
+      console.error(e);
+    });
+  };
+
   return (
     <Grid container>
       <Grid item xs={1} display={'flex'} alignItems={'center'} justifyContent={'center'}>
@@ -43,12 +57,23 @@ const VCSettings = ({ inVC, isMute, isDeafen, isSpeaking, vcName }: Props) => {
           <SettingsPhoneIcon />
         </IconButton>
       </Grid>
-      <Grid item xs={8}>
+      <Grid item xs={7}>
         {vcName}
+        {vcConnection?.average_ping != null && (
+          <Typography
+            variant="caption"
+            color={vcConnection.degraded ? 'error' : 'text.secondary'}
+            title={vcConnection.hostname ?? undefined}
+            marginLeft={1}
+          >
+            {Math.round(vcConnection.average_ping)} ms
+          </Typography>
+        )}
       </Grid>
       <Grid item xs={1} display={'flex'} alignItems={'center'} justifyContent={'center'}>
         <IconButton
           aria-label="mute"
+          title={voiceSettingsHint}
           onClick={(e) => {
             e.preventDefault();
             toggleMute();
@@ -72,6 +97,7 @@ const VCSettings = ({ inVC, isMute, isDeafen, isSpeaking, vcName }: Props) => {
       <Grid item xs={1} display={'flex'} alignItems={'center'} justifyContent={'center'}>
         <IconButton
           aria-label="deafen"
+          title={voiceSettingsHint}
           onClick={(e) => {
             e.preventDefault();
             toggleDeafen();
@@ -101,6 +127,17 @@ const VCSettings = ({ inVC, isMute, isDeafen, isSpeaking, vcName }: Props) => {
           <CallEndIcon />
         </IconButton>
       </Grid>
+      <Grid item xs={1} display={'flex'} alignItems={'center'} justifyContent={'center'}>
+        <IconButton
+          aria-label="logout"
+          onClick={(e) => {
+            e.preventDefault();
+            logout();
+          }}
+        >
+          <LogoutIcon />
+        </IconButton>
+      </Grid>
     </Grid>
   );
 };
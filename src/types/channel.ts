@@ -0,0 +1,20 @@
+// returned by `list_guilds`
+export type Guild = {
+  id: string;
+  name: string;
+  icon_url: string | null;
+};
+
+// returned by `list_channels`, only voice (2) and stage (13) channels
+export type GuildChannel = {
+  id: string;
+  name: string;
+  type: number;
+};
+
+// kept in the config, returned by `list_favorite_channels`
+export type FavoriteChannel = {
+  id: string;
+  name: string;
+  guild_id: string | null;
+};
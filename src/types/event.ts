@@ -1,5 +1,26 @@
+export type Pan = {
+  left: number;
+  right: number;
+};
+
+// returned by `get_user_voice_settings` and `set_user_voice_settings`
+// `get_user_voice_settings` only knows the members of our current voice channel
+// (from joining it and the VOICE_STATE events since), others are an `UnknownMember` error
+export type UserVoiceSettings = {
+  user_id: string;
+  pan: Pan;
+  // 0-200
+  volume: number;
+  // muted for us only
+  mute: boolean;
+};
+
 export type VoiceState = {
   nick: string;
+  // muted for us only, the server mute is in voice_state
+  mute: boolean;
+  volume: number;
+  pan: Pan;
   voice_state: {
     mute: boolean;
     deaf: boolean;
@@ -27,6 +48,56 @@ export type VCMuteUpdatePayload = {
   deaf: boolean;
 };
 
+export type AudioDevice = {
+  id: string;
+  name: string;
+};
+
+export type VoiceIo = {
+  device_id: string;
+  // 0-100 for input, 0-200 for output
+  volume: number;
+  available_devices: AudioDevice[];
+};
+
+export type VoiceMode = {
+  type: 'PUSH_TO_TALK' | 'VOICE_ACTIVITY';
+  auto_threshold: boolean;
+  // -100 to 0 dB
+  threshold: number;
+  shortcut: {
+    // 0: keyboard key, 1: mouse button, 2: keyboard modifier key, 3: gamepad button
+    type: number;
+    code: number;
+    name: string;
+  }[];
+  // push to talk release delay in ms
+  delay: number;
+};
+
+// returned by `get_voice_settings`, `set_voice_settings` and sent with `voice_settings`
+export type VoiceSettings = {
+  mute: boolean;
+  deaf: boolean;
+  input?: VoiceIo;
+  output?: VoiceIo;
+  mode?: VoiceMode;
+  automatic_gain_control?: boolean;
+  echo_cancellation?: boolean;
+  noise_suppression?: boolean;
+  qos?: boolean;
+  silence_warning?: boolean;
+};
+
+// argument of `set_voice_settings`, only the given fields are changed
+export type SetVoiceSettings = Partial<
+  Omit<VoiceSettings, 'input' | 'output' | 'mode'> & {
+    input: Partial<Pick<VoiceIo, 'device_id' | 'volume'>>;
+    output: Partial<Pick<VoiceIo, 'device_id' | 'volume'>>;
+    mode: Partial<VoiceMode>;
+  }
+>;
+
 // LEAVE event does not have data field but theyre not used in event processing
 export type VCUserPayload = {
   event: 'JOIN' | 'UPDATE' | 'LEAVE';
@@ -39,6 +110,9 @@ export type VCUserPayload = {
     self_mute: boolean;
     deaf: boolean;
     self_deaf: boolean;
+    local_mute: boolean;
+    volume: number;
+    pan: Pan;
   };
 };
 
@@ -47,3 +121,85 @@ export type VCSpeakPayload = {
   is_me: boolean;
   speaking: boolean;
 };
+
+export type VoiceConnectionState =
+  | 'DISCONNECTED'
+  | 'AWAITING_ENDPOINT'
+  | 'AUTHENTICATING'
+  | 'CONNECTING'
+  | 'CONNECTED'
+  | 'VOICE_DISCONNECTED'
+  | 'VOICE_CONNECTING'
+  | 'VOICE_CONNECTED'
+  | 'NO_ROUTE'
+  | 'ICE_CHECKING'
+  | 'UNKNOWN';
+
+// pings are in ms
+export type VCConnectionPayload = {
+  state: VoiceConnectionState | null;
+  hostname: string | null;
+  average_ping: number | null;
+  last_ping: number | null;
+  // oldest first
+  pings: number[];
+  degraded: boolean;
+};
+
+export type VCConnectionWarningPayload =
+  | { reason: 'state'; state: VoiceConnectionState }
+  | { reason: 'latency'; average_ping: number; threshold: number };
+
+export type ReconnectingPayload = {
+  attempt: number;
+  delay_ms: number;
+};
+
+// returned by `get_connection_state`
+export type ConnectionState =
+  | 'Disconnected'
+  | 'Connecting'
+  | 'Authorizing'
+  | 'Authenticating'
+  | 'Ready'
+  | 'InVoice';
+
+export type ConnectionStatePayload = {
+  state: ConnectionState;
+  previous: ConnectionState;
+};
+
+// returned by `get_vc_state`
+export type VoiceChannelState = {
+  user_id: string | null;
+  channel: {
+    id: string;
+    name: string;
+    guild_id: string | null;
+  } | null;
+  members: {
+    id: string;
+    username: string;
+    global_name: string | null;
+    avatar: string | null;
+    nick: string;
+    mute: boolean;
+    deaf: boolean;
+    self_mute: boolean;
+    self_deaf: boolean;
+    speaking: boolean;
+    local_mute: boolean;
+    volume: number;
+    pan: Pan;
+  }[];
+  mute: boolean;
+  deaf: boolean;
+  voice_settings: VoiceSettings | null;
+  voice_connection: VCConnectionPayload;
+};
+
+export type TokenRefreshedPayload = {
+  // unix time in seconds
+  expires_at: number;
+  scopes: string[];
+};
@@ -0,0 +1,12 @@
+export type DiscordInstance = {
+  // pass this to `connect_ipc` as `instance` to stick to this client
+  path: string;
+  sandbox: 'Native' | 'Flatpak' | 'Snap';
+  release: 'Stable' | 'Ptb' | 'Canary';
+  user: {
+    id: string;
+    username: string;
+    avatar: string | null;
+    global_name: string | null;
+  } | null;
+};
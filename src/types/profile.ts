@@ -0,0 +1,16 @@
+export type Profile = {
+  name: string;
+  // who the profile last authenticated as
+  user: {
+    id: string;
+    username: string;
+    avatar: string | null;
+    global_name: string | null;
+  } | null;
+};
+
+// returned by `list_profiles`
+export type ProfileList = {
+  active: string;
+  profiles: Profile[];
+};
@@ -6,7 +6,12 @@ export type IpcErrorType =
   | 'Subscribe'
   | 'EventReceive'
   | 'EventSend'
-  | 'EventEncode';
+  | 'EventEncode'
+  | 'Timeout'
+  | 'Closed'
+  | 'State'
+  | 'MissingScope'
+  | 'UnknownMember';
 
 export type IpcError = {
   error_type: IpcErrorType;
@@ -14,7 +19,20 @@ export type IpcError = {
   payload?: unknown;
 };
 
-export type AuthErrorType = 'TokenFetch' | 'RefreshToken' | 'ConfigRead' | 'ConfigSave' | 'Decode' | 'IpcSend';
+export type AuthErrorType =
+  | 'TokenFetch'
+  | 'RefreshToken'
+  | 'ConfigRead'
+  | 'ConfigSave'
+  | 'Decode'
+  | 'IpcSend'
+  | 'InvalidGrant'
+  | 'InvalidClient'
+  | 'RateLimited'
+  | 'Revoke'
+  | 'Profile'
+  | 'MissingScopes'
+  | 'NoRefreshToken';
 
 export type AuthError = {
   error_type: AuthErrorType;
@@ -22,3 +40,15 @@ export type AuthError = {
 };
 
 export type RustError = IpcError | AuthError;
+
+/** The scope a `MissingScope` error asks for. */
+export const missingScope = (e: RustError): string | undefined =>
+  e.error_type === 'MissingScope' && 'payload' in e
+    ? (e.payload as { scope?: string } | undefined)?.scope
+    : undefined;
+
+/** Whether discord closed the connection for a reason reconnecting can't fix. */
+export const isFatalClose = (e: RustError): boolean =>
+  e.error_type === 'Closed' && 'payload' in e
+    ? (e.payload as { fatal?: boolean } | undefined)?.fatal === true
+    : false;